@@ -0,0 +1,137 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoffBuilder;
+use futures::future::join_all;
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use crate::config::Cli;
+
+// Waiting for dependencies outside of the Pod's containers, like an init container would.
+
+/// External dependencies to wait for before running the main program.
+#[derive(Clone, Debug, Default)]
+pub struct Dependencies {
+    /// HOST:PORT addresses that must accept TCP connections.
+    pub tcp: Vec<String>,
+    /// Files that must exist.
+    pub files: Vec<PathBuf>,
+    /// How long to wait for all of them.
+    pub timeout: Duration,
+}
+
+impl From<&Cli> for Dependencies {
+    fn from(cli: &Cli) -> Self {
+        Dependencies {
+            tcp: cli.wait_for_tcp.clone(),
+            files: cli.wait_for_file.clone(),
+            timeout: Duration::from_secs(cli.wait_for_timeout),
+        }
+    }
+}
+
+/// Wait until all the dependencies are available, or return an error if they aren't available before the timeout.
+#[tracing::instrument(skip_all)]
+pub async fn wait_for_dependencies(deps: &Dependencies) -> Result<(), Error> {
+    let checks: Vec<Check> = deps
+        .tcp
+        .iter()
+        .map(|addr| Check::Tcp(addr.clone()))
+        .chain(deps.files.iter().map(|path| Check::File(path.clone())))
+        .collect();
+    if checks.is_empty() {
+        return Ok(());
+    }
+
+    let timeout = deps.timeout;
+    info!(count = checks.len(), ?timeout, "Waiting for dependencies");
+    tokio::time::timeout(timeout, join_all(checks.into_iter().map(wait_for)))
+        .await
+        .map_err(|_| anyhow!("Dependencies weren't available within {:?}", timeout))?;
+    Ok(())
+}
+
+/// Something external that the main process depends on.
+#[derive(Debug)]
+enum Check {
+    /// A HOST:PORT that must accept TCP connections.
+    Tcp(String),
+    /// A file that must exist.
+    File(PathBuf),
+}
+
+impl Check {
+    /// Return true if the dependency is available right now.
+    async fn is_satisfied(&self) -> bool {
+        match self {
+            Check::Tcp(addr) => TcpStream::connect(addr.as_str()).await.is_ok(),
+            Check::File(path) => tokio::fs::metadata(path).await.is_ok(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Tcp(addr) => write!(f, "tcp {}", addr),
+            Check::File(path) => write!(f, "file {}", path.display()),
+        }
+    }
+}
+
+/// Poll a check with exponential backoff until it passes. This never gives up; the caller is responsible for the timeout.
+async fn wait_for(check: Check) {
+    let mut backoff = ExponentialBackoffBuilder::new()
+        .with_max_interval(Duration::from_secs(5))
+        .with_max_elapsed_time(None)
+        .build();
+    while !check.is_satisfied().await {
+        let delay = backoff.next_backoff().unwrap_or(backoff.max_interval);
+        debug!(%check, ?delay, "Dependency not available yet");
+        tokio::time::sleep(delay).await;
+    }
+    info!(%check, "Dependency is available");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn check_satisfied() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        assert!(Check::Tcp(addr).is_satisfied().await);
+
+        assert!(Check::File("Cargo.toml".into()).is_satisfied().await);
+        assert!(!Check::File("does/not/exist".into()).is_satisfied().await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dependency_timeout() -> Result<(), Error> {
+        let cli = Cli::try_parse_from([
+            "proa",
+            "--wait-for-file=does/not/exist",
+            "--wait-for-timeout=1",
+            "true",
+        ])?;
+        assert!(wait_for_dependencies(&(&cli).into()).await.is_err());
+
+        let deps = Dependencies {
+            files: vec!["Cargo.toml".into()],
+            timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        wait_for_dependencies(&deps).await?;
+
+        Ok(())
+    }
+}
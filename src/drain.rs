@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Error};
+use reqwest::{Client, Url};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// How often to check how many connections a sidecar still has.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where to find how many active connections a sidecar has, so we can wait for them to drain before shutting it down.
+#[derive(Clone, Debug)]
+pub struct DrainConfig {
+    /// The URL of the sidecar's metrics.
+    pub url: Url,
+    /// How to find the number of active connections in the metrics.
+    pub metric: Metric,
+}
+
+/// How to find a number in a metrics response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// A JSONPath into a JSON response. If it selects several numbers, they're added up.
+    JsonPath(String),
+    /// The name of a metric in the Prometheus text format. If it has several series, they're added up.
+    Prometheus(String),
+}
+
+/// Poll the sidecar's metrics until it reports no active connections, or until the timeout passes. If the metrics can't be read,
+/// give up waiting rather than spend the whole grace period on it.
+#[tracing::instrument(skip_all)]
+pub async fn wait_for_drain(config: &DrainConfig, client: &Client, timeout: Duration) {
+    let start = Instant::now();
+    loop {
+        // A sidecar that accepts the connection but never answers mustn't hold up the shutdown.
+        let remaining = timeout.saturating_sub(start.elapsed());
+        match active_connections(client, config, remaining).await {
+            Ok(count) if count <= 0.0 => {
+                info!(elapsed = ?start.elapsed(), "Connections have drained");
+                return;
+            }
+            Ok(count) => debug!(count, "Waiting for connections to drain"),
+            Err(err) => {
+                warn!(
+                    err = format!("{:#}", err),
+                    url = config.url.to_string(),
+                    "Unable to read active connections; not waiting for them to drain"
+                );
+                return;
+            }
+        }
+        if start.elapsed() + POLL_INTERVAL > timeout {
+            warn!(?timeout, "Connections didn't drain in time");
+            return;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Fetch the metrics and find the number of active connections.
+async fn active_connections(
+    client: &Client,
+    config: &DrainConfig,
+    timeout: Duration,
+) -> Result<f64, Error> {
+    let body = client
+        .get(config.url.clone())
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    extract(&config.metric, &body)
+}
+
+/// Find the metric in a response body.
+fn extract(metric: &Metric, body: &str) -> Result<f64, Error> {
+    match metric {
+        Metric::JsonPath(path) => {
+            let json: serde_json::Value = serde_json::from_str(body)?;
+            let found = jsonpath_lib::select(&json, path).map_err(|err| anyhow!("{:?}", err))?;
+            let numbers: Vec<f64> = found.iter().filter_map(|v| v.as_f64()).collect();
+            if numbers.is_empty() {
+                return Err(anyhow!("{} didn't select any numbers", path));
+            }
+            Ok(numbers.iter().sum())
+        }
+        Metric::Prometheus(name) => {
+            let numbers: Vec<f64> = body
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+                .filter_map(|line| prometheus_value(line, name))
+                .collect();
+            if numbers.is_empty() {
+                return Err(anyhow!("No samples of metric {}", name));
+            }
+            Ok(numbers.iter().sum())
+        }
+    }
+}
+
+/// If this line of Prometheus text is a sample of the named metric, return its value.
+fn prometheus_value(line: &str, name: &str) -> Option<f64> {
+    let rest = line.strip_prefix(name)?;
+    // Skip the labels, if any. They can contain spaces, so the value comes after the closing brace.
+    let rest = match rest.strip_prefix('{') {
+        Some(labels) => &labels[labels.rfind('}')? + 1..],
+        None => rest,
+    };
+    // Make sure this isn't a different metric whose name starts with this one.
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_json() -> Result<(), Error> {
+        let body = r#"{"listeners": [{"active": 2}, {"active": 3}], "other": 7}"#;
+        let metric = Metric::JsonPath("$.listeners[*].active".to_string());
+        assert_eq!(extract(&metric, body)?, 5.0);
+
+        let metric = Metric::JsonPath("$.missing".to_string());
+        assert!(extract(&metric, body).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn extract_prometheus() -> Result<(), Error> {
+        let body = "\
+# HELP downstream_cx_active Active connections
+# TYPE downstream_cx_active gauge
+downstream_cx_active{listener=\"a b\"} 2
+downstream_cx_active{listener=\"c\"} 1
+downstream_cx_active_total 40
+";
+        let metric = Metric::Prometheus("downstream_cx_active".to_string());
+        assert_eq!(extract(&metric, body)?, 3.0);
+
+        let metric = Metric::Prometheus("upstream_cx_active".to_string());
+        assert!(extract(&metric, body).is_err());
+
+        assert_eq!(prometheus_value("up 1", "up"), Some(1.0));
+        assert_eq!(prometheus_value("upper 1", "up"), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unanswered_metrics() -> Result<(), Error> {
+        // The sidecar accepts the connection, but never answers.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/stats", listener.local_addr()?))?;
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(conn);
+            Ok::<_, std::io::Error>(())
+        });
+        let config = DrainConfig {
+            url,
+            metric: Metric::Prometheus("downstream_cx_active".to_string()),
+        };
+        let start = Instant::now();
+        wait_for_drain(&config, &Client::new(), Duration::from_millis(500)).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        server.abort();
+
+        Ok(())
+    }
+}
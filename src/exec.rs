@@ -1,30 +1,386 @@
 use anyhow::Context;
+use futures::future::OptionFuture;
+#[cfg(unix)]
+use nix::sys::signal::{self, Signal};
+#[cfg(unix)]
+use nix::unistd::Pid;
 use std::ffi::OsString;
-use std::process::{Command, ExitStatus};
-use tracing::{debug_span, info};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
 
-/// Run the main program. Pass its stdout and stderr through to the same places as ours. Capture its return status.
-pub fn run(cmd: &OsString, args: &Vec<OsString>) -> Result<u8, anyhow::Error> {
-    let span = debug_span!("run");
-    let _enter = span.enter();
+use crate::config::Cli;
 
+/// Our exit status when the command doesn't exist, the same as a shell's.
+pub const EXIT_NOT_FOUND: u8 = 127;
+/// Our exit status when the command exists but can't be executed, the same as a shell's.
+pub const EXIT_CANNOT_EXECUTE: u8 = 126;
+
+/// Our exit status when we're asked to terminate before the command starts, the same as a shell's for SIGTERM.
+pub const EXIT_TERMINATED: u8 = 128 + 15;
+
+/// Our exit status when --deadline passes, the same as timeout(1)'s.
+pub const EXIT_DEADLINE: u8 = 124;
+
+/// Our exit status when the Pod doesn't become ready, so the command never runs.
+pub const EXIT_NOT_READY: u8 = 64;
+/// Our exit status when the command succeeded but shutting down the sidecars failed.
+pub const EXIT_SHUTDOWN_FAILED: u8 = 65;
+
+/// The log target for the program's captured output, which --quiet doesn't hide.
+pub const OUTPUT_TARGET: &str = "proa::output";
+
+/// proa's --deadline passed.
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The deadline passed")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Wait until the deadline passes, or forever if there isn't one.
+pub async fn deadline_passed(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Requests for us to terminate: SIGTERM on Unix, or a console shutdown event on Windows.
+pub struct Termination {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    signal: tokio::signal::windows::CtrlShutdown,
+}
+
+impl Termination {
+    /// Start listening for termination requests. Any that arrive before this is called are missed.
+    pub fn listen() -> Result<Self, anyhow::Error> {
+        #[cfg(unix)]
+        let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .context("Failed to listen for SIGTERM")?;
+        #[cfg(windows)]
+        let signal = tokio::signal::windows::ctrl_shutdown()
+            .context("Failed to listen for console shutdown events")?;
+        Ok(Termination { signal })
+    }
+
+    /// Wait for the next termination request. Returns None if no more can arrive.
+    pub async fn recv(&mut self) -> Option<()> {
+        self.signal.recv().await
+    }
+}
+
+/// Options controlling how the main program is run.
+#[derive(Debug)]
+pub struct RunOptions {
+    /// Log each line of the program's stdout and stderr instead of passing them straight through.
+    pub capture_output: bool,
+    /// How many lines of captured output can be waiting to be logged before we stop reading the program's output.
+    pub output_buffer_lines: usize,
+    /// If we're asked to terminate, how long to wait for the program to exit after passing the request along.
+    pub grace_period: Duration,
+    /// A file to write the program's PID to while it's running.
+    pub pid_file: Option<PathBuf>,
+    /// The program's argv[0], if it isn't the command.
+    #[cfg(unix)]
+    pub argv0: Option<OsString>,
+    /// When to stop the program, if it's still running, because proa's --deadline has passed.
+    pub deadline: Option<Instant>,
+    /// Give the program an empty stdin, rather than passing ours through.
+    pub no_stdin: bool,
+}
+
+impl RunOptions {
+    /// Build the options from the command line, plus the Pod's termination grace period. A shorter --main-shutdown-timeout leaves
+    /// the rest of the grace period for the sidecars to shut down once the program has exited.
+    pub fn new(cli: &Cli, grace_period: Duration) -> Self {
+        let grace_period = match cli.main_shutdown_timeout {
+            Some(secs) => Duration::from_secs(secs).min(grace_period),
+            None => grace_period,
+        };
+        RunOptions {
+            capture_output: cli.capture_output,
+            output_buffer_lines: cli.output_buffer_lines,
+            grace_period,
+            pid_file: cli.pid_file.clone(),
+            #[cfg(unix)]
+            argv0: cli.argv0.clone(),
+            deadline: None,
+            no_stdin: cli.no_stdin,
+        }
+    }
+}
+
+/// How the main program ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// It exited with this status. A status that doesn't fit in a byte, which can happen on Windows, is 1.
+    Exited(u8),
+    /// A signal killed it.
+    Signaled(i32),
+    /// It couldn't be started, with the status a shell would exit with: 127 if it doesn't exist, or 126 if it can't be run.
+    SpawnFailed(u8),
+    /// We stopped it because --deadline passed.
+    DeadlineExceeded,
+}
+
+impl ExitReason {
+    /// The status for proa to exit with. For a signal, that's 128 plus the signal's number, like a shell's.
+    pub fn code(&self) -> u8 {
+        match *self {
+            ExitReason::Exited(code) | ExitReason::SpawnFailed(code) => code,
+            ExitReason::Signaled(signal) => u8::try_from(128 + signal).unwrap_or(1),
+            ExitReason::DeadlineExceeded => EXIT_DEADLINE,
+        }
+    }
+
+    /// The name of the signal that killed the program, if one did, like "SIGKILL".
+    pub fn signal_name(&self) -> Option<String> {
+        match *self {
+            #[cfg(unix)]
+            ExitReason::Signaled(signal) => Some(match Signal::try_from(signal) {
+                Ok(signal) => signal.as_str().to_string(),
+                Err(_) => signal.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Describe the exit as JSON, like `{"exitCode":137,"signal":"SIGKILL"}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({ "exitCode": self.code() });
+        if let Some(signal) = self.signal_name() {
+            json["signal"] = signal.into();
+        }
+        json
+    }
+}
+
+/// Run the main program. Pass its stdout and stderr through to the same places as ours, or log them if requested. Return how it
+/// ended.
+pub async fn run(
+    cmd: &OsString,
+    args: &[OsString],
+    opts: &RunOptions,
+) -> Result<ExitReason, anyhow::Error> {
+    run_to_exit(cmd, args, opts, async {}).await
+}
+
+/// Like [run], but if we're asked to terminate, `on_terminate` runs alongside the program's own shutdown, and both finish
+/// before this returns.
+#[tracing::instrument(skip_all)]
+pub async fn run_to_exit(
+    cmd: &OsString,
+    args: &[OsString],
+    opts: &RunOptions,
+    on_terminate: impl Future<Output = ()>,
+) -> Result<ExitReason, anyhow::Error> {
     // Build the command to run.
     let mut cmd = Command::new(cmd);
     let cmd = cmd.args(args);
+    #[cfg(unix)]
+    if let Some(argv0) = &opts.argv0 {
+        cmd.arg0(argv0);
+    }
+    if opts.no_stdin {
+        cmd.stdin(Stdio::null());
+    }
+    if opts.capture_output {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    // Listen for SIGTERM before starting the program, so we don't miss one that arrives right after it starts.
+    let mut sigterm = Termination::listen()?;
 
     // Run it and return the status.
     info!(?cmd, "Running");
-    let status = cmd.status().with_context(|| {
-        format!(
-            "Failed to execute {:?} {:?}",
-            cmd.get_program(),
-            cmd.get_args()
-        )
-    })?;
+    // If the program couldn't be started at all, exit with a status that says so, to tell a broken image or command apart from
+    // a program that ran and failed.
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            let status = spawn_failure_code(&err);
+            error!(
+                err = err.to_string(),
+                program = ?cmd.as_std().get_program(),
+                args = ?cmd.as_std().get_args().collect::<Vec<_>>(),
+                status,
+                "Failed to execute the command"
+            );
+            return Ok(ExitReason::SpawnFailed(status));
+        }
+    };
+    if let Some(path) = &opts.pid_file {
+        write_pid_file(path, child.id()).await;
+    }
+
+    // Forward the output, if it's captured, while waiting for the program to exit. The lines go through a bounded queue, so if
+    // logging falls behind then the program blocks on writing its output, rather than our memory use growing without bound.
+    let (tx, rx) = mpsc::channel(opts.output_buffer_lines);
+    let stdout: OptionFuture<_> = child
+        .stdout
+        .take()
+        .map(|out| read_lines(out, "stdout", tx.clone()))
+        .into();
+    let stderr: OptionFuture<_> = child
+        .stderr
+        .take()
+        .map(|err| read_lines(err, "stderr", tx.clone()))
+        .into();
+    drop(tx);
+    let mut deadline_passed_first = false;
+    let wait = async {
+        tokio::select! {
+            status = child.wait() => status,
+            _ = sigterm.recv() => {
+                let (status, ()) = tokio::join!(terminate(&mut child, opts.grace_period), on_terminate);
+                status
+            }
+            _ = deadline_passed(opts.deadline) => {
+                warn!("The deadline passed while the command was running; stopping it");
+                deadline_passed_first = true;
+                stop(&mut child, opts.grace_period).await
+            }
+        }
+    };
+    let (status, _, _, _) = tokio::join!(wait, stdout, stderr, log_lines(rx));
+    if let Some(path) = &opts.pid_file {
+        remove_pid_file(path).await;
+    }
+    let status = status.context("Failed to wait for the command")?;
 
     info!(?cmd, status = status.code(), "Done running");
-    let status = exit_code(status);
-    Ok(status)
+    if deadline_passed_first {
+        return Ok(ExitReason::DeadlineExceeded);
+    }
+    Ok(exit_reason(status))
+}
+
+/// Write the program's PID to a file so sidecars can watch it. Problems are logged, but they don't stop the program.
+async fn write_pid_file(path: &Path, pid: Option<u32>) {
+    let pid = match pid {
+        Some(pid) => pid,
+        None => return,
+    };
+    if let Err(err) = tokio::fs::write(path, format!("{}\n", pid)).await {
+        warn!(
+            err = err.to_string(),
+            path = %path.display(),
+            "Unable to write PID file"
+        );
+    }
+}
+
+/// Remove the PID file once the program has exited.
+async fn remove_pid_file(path: &Path) {
+    if let Err(err) = tokio::fs::remove_file(path).await {
+        warn!(
+            err = err.to_string(),
+            path = %path.display(),
+            "Unable to remove PID file"
+        );
+    }
+}
+
+/// We were asked to terminate, so pass SIGTERM along to the program and give it the grace period to exit before killing it. The
+/// sidecars aren't told to shut down until this returns, so the program can drain before they do.
+#[cfg(unix)]
+async fn terminate(child: &mut Child, grace_period: Duration) -> std::io::Result<ExitStatus> {
+    info!(?grace_period, "Received SIGTERM; passing it to the command");
+    stop(child, grace_period).await
+}
+
+/// Send SIGTERM to the program, and give it the grace period to exit before killing it.
+#[cfg(unix)]
+async fn stop(child: &mut Child, grace_period: Duration) -> std::io::Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        let pid = Pid::from_raw(pid.try_into().unwrap());
+        if let Err(err) = signal::kill(pid, Signal::SIGTERM) {
+            warn!(err = err.desc(), "Unable to send SIGTERM to the command");
+        }
+    }
+    wait_or_kill(child, grace_period).await
+}
+
+/// We were asked to terminate. There's no signal to pass along on Windows, but the program shares our console, so it got the
+/// same shutdown event; give it the grace period to exit before killing it.
+#[cfg(windows)]
+async fn terminate(child: &mut Child, grace_period: Duration) -> std::io::Result<ExitStatus> {
+    info!(
+        ?grace_period,
+        "Received a shutdown event; waiting for the command to exit"
+    );
+    wait_or_kill(child, grace_period).await
+}
+
+/// There's no way to ask the program to exit on Windows, so kill it.
+#[cfg(windows)]
+async fn stop(child: &mut Child, _grace_period: Duration) -> std::io::Result<ExitStatus> {
+    child.kill().await?;
+    child.wait().await
+}
+
+/// Give the program the grace period to exit, and then kill it.
+async fn wait_or_kill(child: &mut Child, grace_period: Duration) -> std::io::Result<ExitStatus> {
+    match tokio::time::timeout(grace_period, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            warn!("The command didn't exit within the grace period; killing it");
+            child.kill().await?;
+            child.wait().await
+        }
+    }
+}
+
+/// Read the program's output line by line and queue each line to be logged. A final line without a trailing newline is still
+/// sent when the output is closed.
+async fn read_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    stream: &'static str,
+    tx: Sender<(&'static str, String)>,
+) {
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if tx.send((stream, line)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                warn!(stream, err = err.to_string(), "Unable to read output");
+                break;
+            }
+        }
+    }
+}
+
+/// Emit each queued line of output as a log message, until all the readers are done.
+async fn log_lines(mut rx: Receiver<(&'static str, String)>) {
+    while let Some((stream, line)) = rx.recv().await {
+        info!(target: OUTPUT_TARGET, stream, "{}", line);
+    }
+}
+
+/// Choose our exit status when the program couldn't be started.
+fn spawn_failure_code(err: &std::io::Error) -> u8 {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+        _ => EXIT_CANNOT_EXECUTE,
+    }
 }
 
 /// Convert ExitStatus to a u8 that we can use as our own exit status.
@@ -36,19 +392,38 @@ fn exit_code(status: ExitStatus) -> u8 {
     }
 }
 
-#[cfg(test)]
+/// Tell whether the program exited, or a signal killed it.
+#[cfg(unix)]
+fn exit_reason(status: ExitStatus) -> ExitReason {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.signal() {
+        Some(signal) => ExitReason::Signaled(signal),
+        None => ExitReason::Exited(exit_code(status)),
+    }
+}
+
+/// Windows has no signals.
+#[cfg(windows)]
+fn exit_reason(status: ExitStatus) -> ExitReason {
+    ExitReason::Exited(exit_code(status))
+}
+
+// These tests run Unix commands like `sh`.
+#[cfg(all(test, unix))]
 mod tests {
     use anyhow::Error;
 
     use super::*;
 
-    #[test]
-    fn run_exit_codes() -> Result<(), Error> {
+    #[tokio::test]
+    async fn run_exit_codes() -> Result<(), Error> {
         #[derive(Debug)]
         struct TestCase<'a> {
             name: &'a str,
             cmd: &'a str,
             args: Vec<&'a str>,
+            capture_output: bool,
             stat: u8,
         }
 
@@ -57,34 +432,292 @@ mod tests {
                 name: "simple",
                 cmd: "true",
                 args: vec![],
+                capture_output: false,
                 stat: 0,
             },
             TestCase {
                 name: "error",
                 cmd: "false",
                 args: vec![],
+                capture_output: false,
                 stat: 1,
             },
             TestCase {
                 name: "error 5",
                 cmd: "sh",
                 args: vec!["-c", "exit 5"],
+                capture_output: false,
                 stat: 5,
             },
             TestCase {
                 name: "non-u8 err",
                 cmd: "sh",
                 args: vec!["-c", "exit 257"],
+                capture_output: false,
                 stat: 1,
             },
+            TestCase {
+                name: "not found",
+                cmd: "/nonexistent/proa-test-command",
+                args: vec![],
+                capture_output: false,
+                stat: EXIT_NOT_FOUND,
+            },
+            TestCase {
+                name: "not executable",
+                cmd: "/dev/null",
+                args: vec![],
+                capture_output: false,
+                stat: EXIT_CANNOT_EXECUTE,
+            },
+            TestCase {
+                name: "captured output",
+                cmd: "sh",
+                args: vec!["-c", "echo out; echo err >&2; exit 5"],
+                capture_output: true,
+                stat: 5,
+            },
+            TestCase {
+                name: "captured output without final newline",
+                cmd: "sh",
+                args: vec!["-c", "seq 100; printf partial"],
+                capture_output: true,
+                stat: 0,
+            },
         ];
 
         for tc in tests {
-            let args = tc.args.into_iter().map(|x| x.into()).collect();
-            let exit_status = run(&tc.cmd.into(), &args)?;
-            assert_eq!(exit_status, tc.stat, "{}", tc.name);
+            let args: Vec<OsString> = tc.args.into_iter().map(|x| x.into()).collect();
+            let opts = RunOptions {
+                capture_output: tc.capture_output,
+                output_buffer_lines: 1,
+                grace_period: Duration::from_secs(1),
+                pid_file: None,
+                argv0: None,
+                deadline: None,
+                no_stdin: false,
+            };
+            let exit_status = run(&tc.cmd.into(), &args, &opts).await?;
+            assert_eq!(exit_status.code(), tc.stat, "{}", tc.name);
         }
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn partial_final_line() -> Result<(), Error> {
+        let mut child = Command::new("sh")
+            .args(["-c", "seq 100; printf partial"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (tx, mut rx) = mpsc::channel(1);
+        let reader = tokio::spawn(read_lines(child.stdout.take().unwrap(), "stdout", tx));
+        let mut lines = Vec::new();
+        while let Some((stream, line)) = rx.recv().await {
+            assert_eq!(stream, "stdout");
+            lines.push(line);
+        }
+        reader.await?;
+        child.wait().await?;
+
+        // Every line is forwarded, including the last one, which has no newline.
+        assert_eq!(lines.len(), 101);
+        assert_eq!(lines[0], "1");
+        assert_eq!(lines.last().map(String::as_str), Some("partial"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn main_shutdown_timeout() -> Result<(), Error> {
+        use clap::Parser;
+
+        let grace = Duration::from_secs(30);
+        let cli = Cli::try_parse_from(["proa", "true"])?;
+        assert_eq!(RunOptions::new(&cli, grace).grace_period, grace);
+        let cli = Cli::try_parse_from(["proa", "--main-shutdown-timeout=5", "true"])?;
+        assert_eq!(
+            RunOptions::new(&cli, grace).grace_period,
+            Duration::from_secs(5)
+        );
+        // The kubelet won't wait longer than the grace period, so neither do we.
+        let cli = Cli::try_parse_from(["proa", "--main-shutdown-timeout=60", "true"])?;
+        assert_eq!(RunOptions::new(&cli, grace).grace_period, grace);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deadline() -> Result<(), Error> {
+        let opts = RunOptions {
+            capture_output: false,
+            output_buffer_lines: 1,
+            grace_period: Duration::from_secs(1),
+            pid_file: None,
+            argv0: None,
+            deadline: Some(Instant::now() + Duration::from_millis(100)),
+            no_stdin: false,
+        };
+        let exit_status = run(&"sleep".into(), &["10".into()], &opts).await?;
+        assert_eq!(exit_status, ExitReason::DeadlineExceeded);
+        assert_eq!(exit_status.code(), EXIT_DEADLINE);
+
+        // A program that finishes in time keeps its own status.
+        let opts = RunOptions {
+            deadline: Some(Instant::now() + Duration::from_secs(10)),
+            ..opts
+        };
+        let exit_status = run(&"false".into(), &[], &opts).await?;
+        assert_eq!(exit_status, ExitReason::Exited(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exit_details() -> Result<(), Error> {
+        let opts = RunOptions {
+            capture_output: false,
+            output_buffer_lines: 1,
+            grace_period: Duration::from_secs(1),
+            pid_file: None,
+            argv0: None,
+            deadline: None,
+            no_stdin: false,
+        };
+        let args: Vec<OsString> = vec!["-c".into(), "exit 3".into()];
+        let exit = run(&"sh".into(), &args, &opts).await?;
+        assert_eq!(exit, ExitReason::Exited(3));
+        assert_eq!(exit.to_json(), serde_json::json!({ "exitCode": 3 }));
+
+        // A signal is reported the way a shell would, as 128 plus its number.
+        let args: Vec<OsString> = vec!["-c".into(), "kill -9 $$".into()];
+        let exit = run(&"sh".into(), &args, &opts).await?;
+        assert_eq!(exit, ExitReason::Signaled(9));
+        assert_eq!(exit.signal_name().as_deref(), Some("SIGKILL"));
+        assert_eq!(
+            exit.to_json(),
+            serde_json::json!({ "exitCode": 137, "signal": "SIGKILL" })
+        );
+
+        let exit = run(&"/nonexistent/proa-test-command".into(), &[], &opts).await?;
+        assert_eq!(exit, ExitReason::SpawnFailed(EXIT_NOT_FOUND));
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn argv0() -> Result<(), Error> {
+        let opts = RunOptions {
+            capture_output: false,
+            output_buffer_lines: 1,
+            grace_period: Duration::from_secs(1),
+            pid_file: None,
+            argv0: Some("proa-test-sh".into()),
+            deadline: None,
+            no_stdin: false,
+        };
+
+        // The command exits with 0 if its own argv[0] is the one we asked for.
+        let args: Vec<OsString> = vec![
+            "-c".into(),
+            "test \"$(tr '\\0' '\\n' < /proc/$$/cmdline | head -n 1)\" = proa-test-sh".into(),
+        ];
+        let exit_status = run(&"sh".into(), &args, &opts).await?;
+        assert_eq!(exit_status, ExitReason::Exited(0));
+
+        Ok(())
+    }
+
+    /// Set in the environment of the test process that [stdin] runs, with its stdin piped in.
+    const STDIN_CHILD_ENV: &str = "PROA_TEST_STDIN_CHILD";
+
+    #[test]
+    fn stdin() -> Result<(), Error> {
+        use std::io::Write;
+
+        // Run the other half of the test in a child process, so it can have its own stdin without touching ours.
+        let mut child = std::process::Command::new(std::env::current_exe()?)
+            .args(["--exact", "exec::tests::stdin_child", "--test-threads=1"])
+            .env(STDIN_CHILD_ENV, "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(b"hello\n")?;
+        let output = child.wait_with_output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stdin_child() -> Result<(), Error> {
+        if std::env::var_os(STDIN_CHILD_ENV).is_none() {
+            return Ok(());
+        }
+
+        let opts = RunOptions {
+            capture_output: false,
+            output_buffer_lines: 1,
+            grace_period: Duration::from_secs(1),
+            pid_file: None,
+            argv0: None,
+            deadline: None,
+            no_stdin: true,
+        };
+        // With --no-stdin the program sees an empty stdin, even though ours has input waiting.
+        let args: Vec<OsString> = vec!["-c".into(), "test -z \"$(cat)\"".into()];
+        assert_eq!(
+            run(&"sh".into(), &args, &opts).await?,
+            ExitReason::Exited(0)
+        );
+
+        // By default it reads what was piped into ours.
+        let opts = RunOptions {
+            no_stdin: false,
+            ..opts
+        };
+        let args: Vec<OsString> = vec!["-c".into(), "test \"$(cat)\" = hello".into()];
+        assert_eq!(
+            run(&"sh".into(), &args, &opts).await?,
+            ExitReason::Exited(0)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pid_file() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("proa-test-{}.pid", std::process::id()));
+        let opts = RunOptions {
+            capture_output: false,
+            output_buffer_lines: 1,
+            grace_period: Duration::from_secs(1),
+            pid_file: Some(path.clone()),
+            argv0: None,
+            deadline: None,
+            no_stdin: false,
+        };
+
+        // The command exits with 0 if the PID file contains its own PID.
+        let args: Vec<OsString> = vec![
+            "-c".into(),
+            format!("sleep 1; test \"$(cat {})\" = $$", path.display()).into(),
+        ];
+        let exit_status = run(&"sh".into(), &args, &opts).await?;
+        assert_eq!(exit_status, ExitReason::Exited(0));
+        assert!(!path.exists());
+
+        // Failing to write the PID file doesn't stop the command.
+        let opts = RunOptions {
+            pid_file: Some("does/not/exist/proa.pid".into()),
+            ..opts
+        };
+        let exit_status = run(&"true".into(), &[], &opts).await?;
+        assert_eq!(exit_status, ExitReason::Exited(0));
+
+        Ok(())
+    }
 }
@@ -1,87 +1,730 @@
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, Context, Error};
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoffBuilder;
+use futures::stream::BoxStream;
 use futures::{Stream, StreamExt, TryStreamExt};
-use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::core::v1::{Container, Pod};
 use kube::{
     runtime::{
-        watcher::{default_backoff, watch_object},
+        reflector,
+        watcher::{self, default_backoff, watcher},
         WatchStreamExt,
     },
     ResourceExt,
 };
 use kube::{Api, Client};
-use tracing::{debug, debug_span, info};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, info, warn, Span};
+
+use crate::config::{glob_match, Cli, ListSemantic, ReadyFile, WatchBackend};
+use crate::status::Status;
 
 // Kubernetes-related functions.
 
+/// How to decide whether the Pod is ready for the main program to run.
+#[derive(Clone, Debug, Default)]
+pub struct ReadyConfig {
+    /// The names of the main containers, which aren't waited for. If this is empty, the main container is found with
+    /// `main_container_annotation`.
+    pub main_containers: Vec<String>,
+    /// A Pod annotation whose value is the name of the main container. If this isn't set, or the Pod doesn't have the annotation,
+    /// the first container in the Pod is the main one.
+    pub main_container_annotation: Option<String>,
+    /// How long to wait for readiness before giving up. If this isn't set, wait indefinitely.
+    pub timeout: Option<Duration>,
+    /// The longest random delay before starting to watch the Pod.
+    pub startup_jitter: Duration,
+    /// How many times to retry creating the Kubernetes client.
+    pub client_init_retries: u32,
+    /// Stop retrying the Kubernetes client after this long.
+    pub client_init_timeout: Duration,
+    /// Treat a sidecar that was OOMKilled as a fatal error, regardless of the Pod's restart policy.
+    pub fatal_on_oom: bool,
+    /// Give up at the first error that won't go away on its own, such as a permissions problem or a terminated sidecar, instead
+    /// of waiting in case it's fixed.
+    pub fail_fast: bool,
+    /// How to watch the Pod.
+    pub watch_backend: WatchBackend,
+    /// How up to date the Pod has to be when the watch starts.
+    pub list_semantic: ListSemantic,
+    /// Use the whole hostname as our Pod name, instead of stripping the domain off it.
+    pub full_hostname: bool,
+    /// Our Pod's name, if it was configured. If it wasn't, it's found from the hostname.
+    pub pod_name: Option<String>,
+    /// Our Pod's namespace, if it was configured. If it wasn't, it's the Kubernetes client's default.
+    pub namespace: Option<String>,
+    /// Treat a Pod with no sidecars as an error, since proa is pointless there and a sidecar may have failed to be injected.
+    pub require_sidecars: bool,
+    /// Globs matching the images of containers that aren't waited for, like the main containers.
+    pub ignore_images: Vec<String>,
+    /// How many sidecars have to be ready, if not all of them.
+    pub quorum: Option<usize>,
+    /// While waiting for the sidecars to exit, count waiting ones, such as ones being restarted, as still running.
+    pub count_waiting: bool,
+    /// Files that have to be present, after the sidecars are ready, before the Pod counts as ready.
+    pub ready_files: Vec<ReadyFile>,
+    /// Where to record what we see of the Pod's readiness, for the status endpoint.
+    pub status: Status,
+}
+
+impl From<&Cli> for ReadyConfig {
+    fn from(cli: &Cli) -> Self {
+        ReadyConfig {
+            main_containers: cli.main_containers(),
+            main_container_annotation: cli.main_container_annotation.clone(),
+            timeout: cli.ready_timeout.map(Duration::from_secs),
+            startup_jitter: Duration::from_millis(cli.startup_jitter),
+            client_init_retries: cli.client_init_retries,
+            client_init_timeout: Duration::from_secs(cli.client_init_timeout),
+            fatal_on_oom: cli.fatal_on_oom,
+            fail_fast: cli.fail_fast,
+            watch_backend: cli.watch_backend,
+            list_semantic: cli.list_semantic,
+            full_hostname: cli.no_strip_hostname,
+            pod_name: cli.pod_name(),
+            namespace: cli.pod_namespace(),
+            require_sidecars: cli.require_sidecars,
+            ignore_images: cli.ignore_image.clone(),
+            quorum: cli.ready_quorum,
+            count_waiting: cli.shutdown_count_waiting,
+            ready_files: cli.wait_for_file_ready.clone(),
+            status: Status::new(cli.event_buffer),
+        }
+    }
+}
+
+/// How many times to start watching the Pod, if the watch keeps ending before the Pod is ready.
+const WATCH_ATTEMPTS: u32 = 3;
+
+/// The Pod watch stream ended cleanly without ever yielding a ready Pod. The kube watcher normally reconnects on its own, so
+/// this is unusual, and is worth telling apart from the Pod never becoming ready.
+#[derive(Debug)]
+pub struct WatchEnded {
+    /// The most recent reason the Pod wasn't ready, if we saw one.
+    pub last_reason: Option<String>,
+}
+
+impl std::fmt::Display for WatchEnded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The Pod watch ended before the Pod was ready")?;
+        write_last_reason(f, &self.last_reason)
+    }
+}
+
+impl std::error::Error for WatchEnded {}
+
+/// The sidecars weren't ready within the configured timeout. Unlike other errors, it's possible they're fine, just slow.
+#[derive(Debug)]
+pub struct ReadyTimeout {
+    /// How long we waited.
+    pub timeout: Duration,
+    /// The most recent reason the Pod wasn't ready, if we saw one.
+    pub last_reason: Option<String>,
+}
+
+impl std::fmt::Display for ReadyTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Timed out after {:?} waiting for the Pod to be ready",
+            self.timeout
+        )?;
+        write_last_reason(f, &self.last_reason)
+    }
+}
+
+impl std::error::Error for ReadyTimeout {}
+
+/// Append the last reason the Pod wasn't ready to an error message, so whoever reads it knows what we were waiting for.
+fn write_last_reason(
+    f: &mut std::fmt::Formatter<'_>,
+    last_reason: &Option<String>,
+) -> std::fmt::Result {
+    match last_reason {
+        Some(reason) => write!(f, " (last seen: {})", reason),
+        None => Ok(()),
+    }
+}
+
+/// The most recent reason the Pod wasn't ready, kept while we watch it so the error can say why if we give up.
+type LastReason = RefCell<Option<String>>;
+
 /// Find the name of our own Pod, identify which container is ours, and watch all the other containers for readiness. Return when
 /// they're ready, or return an error.
-#[tracing::instrument]
-pub async fn wait_for_ready() -> Result<Pod, Error> {
-    let events = watch_my_pod().await?;
-    let ready_pods = events.filter_map(filter_ready);
+#[tracing::instrument(skip_all, fields(pod_name, namespace))]
+pub async fn wait_for_ready(config: &ReadyConfig) -> Result<Pod, Error> {
+    // Spread out the load on the apiserver when many Pods start at once.
+    if !config.startup_jitter.is_zero() {
+        let delay = config.startup_jitter.mul_f64(rand::random::<f64>());
+        debug!(?delay, "Waiting before watching the Pod");
+        tokio::time::sleep(delay).await;
+    }
+
+    let last_reason = LastReason::default();
+    let wait = async {
+        let pod = watch_until_ready(config, &last_reason).await?;
+        wait_for_ready_files(&config.ready_files, &last_reason).await;
+        Ok::<_, Error>(pod)
+    };
+    match config.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| ReadyTimeout {
+                timeout,
+                last_reason: last_reason.take(),
+            })?,
+        None => wait.await,
+    }
+}
+
+/// How often to check for the --wait-for-file-ready files.
+const READY_FILE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Poll until each of the files exists, holding the expected contents if there are any. This never gives up; the caller is
+/// responsible for the timeout.
+async fn wait_for_ready_files(files: &[ReadyFile], last_reason: &LastReason) {
+    for file in files {
+        while !is_ready_file(file).await {
+            last_reason.replace(Some(format!(
+                "Waiting for ready file {}",
+                file.path.display()
+            )));
+            tokio::time::sleep(READY_FILE_INTERVAL).await;
+        }
+        debug!(path = %file.path.display(), "Ready file is present");
+    }
+}
+
+/// Return true if the file exists, and holds the expected contents if there are any.
+async fn is_ready_file(file: &ReadyFile) -> bool {
+    match &file.contents {
+        Some(expected) => tokio::fs::read_to_string(&file.path)
+            .await
+            .map_or(false, |contents| contents.trim() == expected.trim()),
+        None => tokio::fs::metadata(&file.path).await.is_ok(),
+    }
+}
+
+/// Watch our Pod until it's ready, starting the watch again if it ends early.
+async fn watch_until_ready(config: &ReadyConfig, last_reason: &LastReason) -> Result<Pod, Error> {
+    let mut attempt = 1;
+    loop {
+        let events = watch_my_pod(config).await?;
+        match watch_for_ready(events, config, last_reason).await {
+            Err(err) if err.is::<WatchEnded>() && attempt < WATCH_ATTEMPTS => {
+                warn!(attempt, "{}; watching again.", err);
+                attempt += 1;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Watch a stream of events about a Pod until all the containers other than the main one are ready. Return the ready Pod, or
+/// return an error. If the stream ends first, the error is a [WatchEnded].
+pub async fn wait_for_ready_from(
+    events: impl Stream<Item = Result<Option<Pod>, Error>>,
+    config: &ReadyConfig,
+) -> Result<Pod, Error> {
+    watch_for_ready(events, config, &LastReason::default()).await
+}
+
+/// Like [wait_for_ready_from], but remember why the Pod wasn't ready in `last_reason`, which outlives the stream.
+#[tracing::instrument(skip_all)]
+async fn watch_for_ready(
+    events: impl Stream<Item = Result<Option<Pod>, Error>>,
+    config: &ReadyConfig,
+    last_reason: &LastReason,
+) -> Result<Pod, Error> {
+    let start = Instant::now();
+    let ready_pods = events.filter_map(|pod| filter_ready(pod, config, last_reason));
     let mut ready_pods = Box::pin(ready_pods);
 
-    let ready_pod = ready_pods
-        .next()
-        .await
-        .ok_or(anyhow!("Pod was never ready"))?;
-    info!(err = ?ready_pod.as_ref().err(), "Done waiting for Pod.");
+    let ready_pod = match ready_pods.next().await {
+        Some(ready_pod) => ready_pod,
+        None => {
+            return Err(WatchEnded {
+                last_reason: last_reason.borrow().clone(),
+            }
+            .into())
+        }
+    };
+    // How long the sidecars take to become ready is worth watching for capacity planning.
+    info!(
+        err = ?ready_pod.as_ref().err(),
+        elapsed = ?start.elapsed(),
+        "Done waiting for Pod."
+    );
+    match &ready_pod {
+        Ok(_) => config.status.record_event("The Pod is ready"),
+        Err(err) => config
+            .status
+            .record_event(&format!("Stopped waiting for the Pod: {:#}", err)),
+    }
     ready_pod
 }
 
+/// Create a Kubernetes client. Right after the Pod starts, the service account token or the network may not be ready for a
+/// moment, so retry with backoff, within the configured count and timeout.
+pub async fn make_client(config: &ReadyConfig) -> Result<Client, Error> {
+    let mut backoff = ExponentialBackoffBuilder::new()
+        .with_max_elapsed_time(Some(config.client_init_timeout))
+        .build();
+    let mut attempt = 1;
+    loop {
+        let err = match Client::try_default().await {
+            Ok(client) => return Ok(client),
+            Err(err) => err,
+        };
+        let delay = match backoff.next_backoff() {
+            Some(delay) if attempt <= config.client_init_retries => delay,
+            _ => return Err(err).context("Unable to create a Kubernetes client"),
+        };
+        warn!(
+            attempt,
+            err = err.to_string(),
+            ?delay,
+            "Unable to create a Kubernetes client; retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
 /// Return a stream providing Pod events about the pod we're running in.
-pub async fn watch_my_pod() -> Result<impl Stream<Item = Result<Option<Pod>, Error>>, Error> {
-    let client = Client::try_default().await?;
-    let pods_api: Api<Pod> = Api::default_namespaced(client);
+pub async fn watch_my_pod(
+    config: &ReadyConfig,
+) -> Result<BoxStream<'static, Result<Option<Pod>, Error>>, Error> {
+    let client = make_client(config).await?;
+    let myname = my_pod_name(config);
+    record_pod_identity(&myname, &my_namespace(&client, config));
+    info!(myname, backend = ?config.watch_backend, "Watching for Pod");
+
+    Ok(match config.watch_backend {
+        WatchBackend::Watch => watch_pod(client, &myname, config).boxed(),
+        WatchBackend::Reflector => reflect_pod(client, &myname, config).boxed(),
+    })
+}
+
+/// Fetch the Pod we're running in, once.
+pub async fn get_my_pod(config: &ReadyConfig) -> Result<Pod, Error> {
+    let client = make_client(config).await?;
+    let myname = my_pod_name(config);
+    let namespace = my_namespace(&client, config);
+    record_pod_identity(&myname, &namespace);
+    debug!(myname, "Fetching Pod");
+
+    let pods_api: Api<Pod> = Api::namespaced(client, &namespace);
+    Ok(pods_api.get(&myname).await?)
+}
+
+/// Set an annotation on our own Pod. This needs permission to `patch` Pods.
+pub async fn annotate_my_pod(key: &str, value: &str, config: &ReadyConfig) -> Result<(), Error> {
+    use kube::api::{Patch, PatchParams};
+
+    let client = make_client(config).await?;
+    let myname = my_pod_name(config);
+    let pods_api: Api<Pod> = Api::namespaced(client.clone(), &my_namespace(&client, config));
+    let patch = serde_json::json!({ "metadata": { "annotations": { key: value } } });
+    pods_api
+        .patch(&myname, &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+        .with_context(|| format!("Unable to annotate Pod {}", myname))?;
+    Ok(())
+}
+
+/// What came of running a command in a container.
+#[cfg(feature = "kube-exec")]
+#[derive(Debug)]
+pub struct ExecOutput {
+    /// Everything the command wrote to stdout.
+    pub stdout: String,
+    /// Everything the command wrote to stderr.
+    pub stderr: String,
+    /// The status Kubernetes reported for the command, such as "Success" or "Failure".
+    pub status: Option<String>,
+    /// Kubernetes' explanation of the status, such as the command's exit code.
+    pub message: Option<String>,
+}
+
+/// Run a command inside one of the containers in our own Pod, through the Kubernetes exec API. This needs permission to
+/// `create` the `pods/exec` subresource.
+#[cfg(feature = "kube-exec")]
+pub async fn exec_in_my_pod(
+    container: &str,
+    command: &[String],
+    config: &ReadyConfig,
+) -> Result<ExecOutput, Error> {
+    use kube::api::AttachParams;
+    use tokio::io::{AsyncRead, AsyncReadExt};
+
+    async fn read_all(reader: Option<impl AsyncRead + Unpin>) -> String {
+        let mut out = String::new();
+        if let Some(mut reader) = reader {
+            if let Err(err) = reader.read_to_string(&mut out).await {
+                debug!(err = err.to_string(), "Error reading exec output");
+            }
+        }
+        out
+    }
+
+    let client = make_client(config).await?;
+    let myname = my_pod_name(config);
+    let pods_api: Api<Pod> = Api::namespaced(client.clone(), &my_namespace(&client, config));
+    let params = AttachParams::default()
+        .container(container)
+        .stdout(true)
+        .stderr(true);
+    let mut process = pods_api.exec(&myname, command.to_vec(), &params).await?;
+
+    let stdout = process.stdout();
+    let stderr = process.stderr();
+    let status = process.take_status();
+    let (stdout, stderr) = tokio::join!(read_all(stdout), read_all(stderr));
+    let status = match status {
+        Some(status) => status.await,
+        None => None,
+    };
+    process.join().await?;
+
+    Ok(ExecOutput {
+        stdout,
+        stderr,
+        status: status.as_ref().and_then(|s| s.status.clone()),
+        message: status.and_then(|s| s.message),
+    })
+}
+
+/// Fill in the `pod_name` and `namespace` fields of the current span, if it has them, so logs from many Pods can be told apart.
+pub fn record_pod_identity(name: &str, namespace: &str) {
+    Span::current()
+        .record("pod_name", name)
+        .record("namespace", namespace);
+}
+
+/// Our Pod's namespace: the configured one if there is one, or else the client's default, which in a Pod comes from the service
+/// account.
+pub fn my_namespace(client: &Client, config: &ReadyConfig) -> String {
+    config
+        .namespace
+        .clone()
+        .unwrap_or_else(|| client.default_namespace().to_string())
+}
 
-    // Our Pod name is the same as our hostname.
+/// Our Pod name: the configured one if there is one, or else the same as our hostname.
+pub fn my_pod_name(config: &ReadyConfig) -> String {
+    if let Some(name) = &config.pod_name {
+        return name.clone();
+    }
     let myname = gethostname::gethostname();
     let myname = myname.into_string().unwrap();
-    // Strip domain parts off in case setHostnameAsFQDN is set.
-    let myname = myname.split('.').next().unwrap();
-    info!(myname, "Watching for Pod");
+    pod_name_from_hostname(&myname, config.full_hostname)
+}
+
+/// Strip domain parts off the hostname in case setHostnameAsFQDN is set, unless we've been told the whole thing is the Pod name.
+fn pod_name_from_hostname(hostname: &str, full_hostname: bool) -> String {
+    if full_hostname {
+        hostname.to_string()
+    } else {
+        hostname.split('.').next().unwrap().to_string()
+    }
+}
+
+/// Return a stream providing events about the named Pod in our namespace.
+pub fn watch_pod(
+    client: Client,
+    name: &str,
+    config: &ReadyConfig,
+) -> impl Stream<Item = Result<Option<Pod>, Error>> {
+    let namespace = my_namespace(&client, config);
+    let pods_api: Api<Pod> = Api::namespaced(client, &namespace);
+    let name = name.to_string();
+    watcher(pods_api, pod_watcher_config(&name, config))
+        .map_ok(pod_from_event)
+        .backoff(default_backoff())
+        .map_err(|e| anyhow!(e))
+        .map(move |result| result.and_then(|pod| check_pod_identity(pod, &name, &namespace)))
+}
 
-    let pod = watch_object(pods_api, myname)
+/// Like [watch_pod], but keep the Pod in a reflector's cache, and report what's in the cache after each event. The reflector
+/// resyncs the cache when the watch restarts, so a Pod deleted while we weren't watching is noticed.
+pub fn reflect_pod(
+    client: Client,
+    name: &str,
+    config: &ReadyConfig,
+) -> impl Stream<Item = Result<Option<Pod>, Error>> {
+    let namespace = my_namespace(&client, config);
+    let pods_api: Api<Pod> = Api::namespaced(client, &namespace);
+    let name = name.to_string();
+    let (reader, writer) = reflector::store();
+    let events = watcher(pods_api, pod_watcher_config(&name, config)).map_ok(slim_event);
+    reflector(writer, events)
         .backoff(default_backoff())
-        .map_err(|e| anyhow!(e));
-    Ok(pod)
+        .map_err(|e| anyhow!(e))
+        // The field selector means the cache only ever holds our Pod.
+        .map_ok(move |_event| reader.state().first().map(|pod| Pod::clone(pod)))
+        .map(move |result| result.and_then(|pod| check_pod_identity(pod, &name, &namespace)))
+}
+
+/// Turn a watch event into the latest state of our Pod, or None if it doesn't exist. A restarted watch lists the Pod again, which
+/// is a resync rather than a change, and only means the Pod is gone if the list comes back empty; log which it was, so a resync
+/// isn't mistaken for a deletion.
+fn pod_from_event(event: watcher::Event<Pod>) -> Option<Pod> {
+    match event {
+        watcher::Event::Applied(pod) => Some(slim_pod(pod)),
+        watcher::Event::Deleted(pod) => {
+            info!(pod = pod.name_any(), "The Pod was deleted");
+            None
+        }
+        watcher::Event::Restarted(mut pods) => {
+            let pod = pods.pop();
+            match &pod {
+                Some(pod) => debug!(
+                    pod = pod.name_any(),
+                    "The watch restarted and resynced the Pod"
+                ),
+                None => info!("The watch restarted, and the Pod wasn't there"),
+            }
+            pod.map(slim_pod)
+        }
+    }
+}
+
+/// The annotation where `kubectl apply` keeps a copy of the whole object, which can be as big as the rest of the Pod.
+const LAST_APPLIED_ANNOTATION: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
+/// Drop the parts of a Pod that proa never looks at, but that can make up most of its size: the managed fields and kubectl's
+/// copy of the last applied configuration. The watch API has no way to ask for less than the whole object, so the Pod has
+/// already been decoded by now, but everything after this, like the reflector's cache and the copies made for each event,
+/// handles less.
+fn slim_pod(mut pod: Pod) -> Pod {
+    pod.metadata.managed_fields = None;
+    if let Some(annotations) = pod.metadata.annotations.as_mut() {
+        annotations.remove(LAST_APPLIED_ANNOTATION);
+    }
+    pod
+}
+
+/// Apply [slim_pod] to every Pod in a watch event.
+fn slim_event(event: watcher::Event<Pod>) -> watcher::Event<Pod> {
+    match event {
+        watcher::Event::Applied(pod) => watcher::Event::Applied(slim_pod(pod)),
+        watcher::Event::Deleted(pod) => watcher::Event::Deleted(slim_pod(pod)),
+        watcher::Event::Restarted(pods) => {
+            watcher::Event::Restarted(pods.into_iter().map(slim_pod).collect())
+        }
+    }
+}
+
+/// Configure a watch of just the named Pod. By default the initial list comes from etcd, so it's up to date; with
+/// `ListSemantic::Any`, the apiserver can answer from its cache instead, which is cheaper but may be a little stale.
+fn pod_watcher_config(name: &str, config: &ReadyConfig) -> watcher::Config {
+    let watcher_config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+    match config.list_semantic {
+        ListSemantic::MostRecent => watcher_config,
+        ListSemantic::Any => watcher::Config {
+            list_semantic: watcher::ListSemantic::Any,
+            ..watcher_config
+        },
+    }
+}
+
+/// The Pod we were told about isn't the one we asked for, so the Pod name or namespace must be misconfigured.
+#[derive(Debug)]
+pub struct PodMismatch {
+    /// The namespace and name we watched.
+    pub expected: (String, String),
+    /// The namespace and name of the Pod we got.
+    pub actual: (Option<String>, String),
+}
+
+impl std::fmt::Display for PodMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expected Pod {}/{}, but got {}/{}",
+            self.expected.0,
+            self.expected.1,
+            self.actual.0.as_deref().unwrap_or("<no namespace>"),
+            self.actual.1
+        )
+    }
+}
+
+impl std::error::Error for PodMismatch {}
+
+/// The Pod is already being deleted, so it will never be worth running the main program in it.
+#[derive(Debug)]
+pub struct PodTerminating {
+    /// When the Pod was marked for deletion.
+    pub deletion_timestamp: String,
+}
+
+impl std::fmt::Display for PodTerminating {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The Pod is terminating; it was marked for deletion at {}",
+            self.deletion_timestamp
+        )
+    }
+}
+
+impl std::error::Error for PodTerminating {}
+
+/// Make sure the Pod is the one we're watching. That can't go wrong unless something is misconfigured, but watching the wrong
+/// Pod would be confusing, so catch it early.
+fn check_pod_identity(
+    maybe_pod: Option<Pod>,
+    name: &str,
+    namespace: &str,
+) -> Result<Option<Pod>, Error> {
+    if let Some(pod) = &maybe_pod {
+        let pod_namespace = pod.namespace();
+        let namespace_matches = pod_namespace.as_deref().map_or(true, |ns| ns == namespace);
+        if pod.name_any() != name || !namespace_matches {
+            return Err(PodMismatch {
+                expected: (namespace.to_string(), name.to_string()),
+                actual: (pod_namespace, pod.name_any()),
+            }
+            .into());
+        }
+    }
+    Ok(maybe_pod)
+}
+
+/// Kubernetes' default termination grace period, in seconds.
+const DEFAULT_GRACE_PERIOD: u64 = 30;
+
+/// The longest termination grace period we'll believe, in seconds. Anything longer is almost certainly a mistake, and waiting
+/// that long would look like a hang.
+const MAX_GRACE_PERIOD: u64 = 24 * 60 * 60;
+
+/// Return the Pod's termination grace period, or Kubernetes' default of 30 seconds if we don't know it.
+pub fn grace_period(maybe_pod: Option<&Pod>) -> Duration {
+    let seconds: Option<i64> = maybe_pod
+        .and_then(|pod| pod.spec.as_ref())
+        .and_then(|spec| spec.termination_grace_period_seconds);
+    let seconds: u64 = match seconds {
+        Some(x @ 0..) if x as u64 > MAX_GRACE_PERIOD => {
+            warn!(
+                seconds = x,
+                max = MAX_GRACE_PERIOD,
+                "The Pod's terminationGracePeriodSeconds is too long; using the maximum"
+            );
+            MAX_GRACE_PERIOD
+        }
+        Some(x @ 0..) => x.try_into().unwrap(),
+        Some(x) => {
+            warn!(
+                seconds = x,
+                default = DEFAULT_GRACE_PERIOD,
+                "The Pod's terminationGracePeriodSeconds is negative; using the default"
+            );
+            DEFAULT_GRACE_PERIOD
+        }
+        None => {
+            debug!("Defaulting to {} seconds", DEFAULT_GRACE_PERIOD);
+            DEFAULT_GRACE_PERIOD
+        }
+    };
+    Duration::new(seconds, 0)
 }
 
 /// If we're done waiting for readiness, return something: either the ready Pod or an error.
-/// If we're not done waiting, return None.
-async fn filter_ready(pod: Result<Option<Pod>, Error>) -> Option<Result<Pod, Error>> {
+/// If we're not done waiting, return None, and record why in `last_reason`.
+async fn filter_ready(
+    pod: Result<Option<Pod>, Error>,
+    config: &ReadyConfig,
+    last_reason: &LastReason,
+) -> Option<Result<Pod, Error>> {
+    let keep_waiting = |reason: String| {
+        // The same reason tends to come up over and over, so only log changes.
+        if last_reason.borrow().as_ref() != Some(&reason) {
+            config.status.record_event(&reason);
+        }
+        last_reason.replace(Some(reason));
+        None
+    };
     match pod {
         Err(e) => {
-            info!("Watch error: {}", e);
-            None
+            let kind = WatchErrorKind::classify(&e);
+            if kind.is_retriable() {
+                debug!(?kind, "Watch error: {}", e);
+                keep_waiting(format!("Watch error: {}", e))
+            } else {
+                warn!(?kind, "Watch error: {}. {}", e, kind.guidance());
+                // Watching the wrong Pod won't fix itself, so don't wait for it to.
+                let fatal = config.fail_fast || e.is::<PodMismatch>();
+                if fatal {
+                    Some(Err(e))
+                } else {
+                    keep_waiting(format!("Watch error: {}", e))
+                }
+            }
         }
         Ok(None) => {
-            debug!("Pod was deleted?");
-            None
+            debug!("The Pod doesn't exist");
+            keep_waiting(
+                "The Pod doesn't exist; it was deleted, or hasn't been created yet".to_string(),
+            )
         }
         Ok(Some(p)) => {
             debug!("Saw Pod {}...", p.name_any());
-            match is_ready(&p) {
+            // If the Pod was deleted before it got going, readiness may never come, so stop waiting for it.
+            if let Some(time) = &p.metadata.deletion_timestamp {
+                return Some(Err(PodTerminating {
+                    deletion_timestamp: time.0.to_rfc3339(),
+                }
+                .into()));
+            }
+            let result = is_ready(&p, config);
+            if let Ok(names) = main_cont_names(&p, config) {
+                // A flapping sidecar slows down startup without ever failing it, so make each restart visible.
+                for (container, restarts) in config.status.record_restarts(&p, &names) {
+                    info!(
+                        container,
+                        restarts, "Sidecar restarted while waiting for readiness"
+                    );
+                    config.status.record_event(&format!(
+                        "Sidecar {} restarted {} times",
+                        container, restarts
+                    ));
+                }
+                config
+                    .status
+                    .observe(&compute_readiness(&p, &names, config));
+            }
+            match result {
                 // Keep waiting for readiness.
-                WatchResult::NotReady => None,
-                // If we see a k8s API error, log it and keep waiting.
+                WatchResult::NotReady(reason) => keep_waiting(reason),
+                // If we see a k8s API error, log it and keep waiting, unless we're failing fast.
+                WatchResult::ApiError(e) if config.fail_fast => Some(Err(e)),
                 WatchResult::ApiError(e) => {
-                    info!("Unsure if ready: {}", e);
-                    None
+                    info!("Unsure if ready: {:#}", e);
+                    keep_waiting(format!("{:#}", e))
                 }
                 // If all the sidecars are ready, return the Pod.
                 WatchResult::Ready => Some(Ok(p)),
-                // One of the sidecars terminated.
-                WatchResult::PodError(e) => {
-                    if p.spec
-                        .map(|s| s.restart_policy == Some("Never".to_string()))
-                        .unwrap_or(true)
+                // There's no point in waiting, whatever the restart policy.
+                WatchResult::FatalError(e) => Some(Err(e)),
+                // Don't wait for k8s to restart the sidecars if we're failing fast.
+                WatchResult::SidecarTerminated(mut terminated) if config.fail_fast => {
+                    Some(Err(terminated.remove(0).1))
+                }
+                // Some of the sidecars terminated.
+                WatchResult::SidecarTerminated(mut terminated) => {
+                    match terminated
+                        .iter()
+                        .position(|(container, _)| !will_restart_sidecar(&p, container))
                     {
-                        // If restartPolicy == Never, then return an error because there's no point in waiting.
-                        Some(Err(e))
-                    } else {
-                        // Any other restartPolicy means k8s will restart the sidecar; we should keep waiting for readiness.
-                        None
+                        // If one of them won't be restarted, then return an error because there's no point in waiting.
+                        Some(i) => Some(Err(terminated.remove(i).1)),
+                        // k8s will restart them all; we should keep waiting for readiness.
+                        None => keep_waiting(format!("{:#}", terminated[0].1)),
                     }
                 }
             }
@@ -89,84 +732,492 @@ async fn filter_ready(pod: Result<Option<Pod>, Error>) -> Option<Result<Pod, Err
     }
 }
 
+/// Return true if Kubernetes will restart the Pod's terminated sidecar `container`. It never does under restartPolicy Never,
+/// and under OnFailure it only restarts containers that failed, so a sidecar that exited 0 is gone for good. Under Always,
+/// the default, it always does.
+fn will_restart_sidecar(pod: &Pod, container: &str) -> bool {
+    let policy = match &pod.spec {
+        Some(spec) => spec.restart_policy.as_deref().unwrap_or("Always"),
+        None => return false,
+    };
+    match policy {
+        "Never" => false,
+        "OnFailure" => {
+            let succeeded = pod
+                .status
+                .iter()
+                .flat_map(|s| s.container_statuses.iter().flatten())
+                .filter(|s| s.name == container)
+                .filter_map(|s| s.state.as_ref()?.terminated.as_ref())
+                .any(|term| term.exit_code == 0);
+            !succeeded
+        }
+        _ => true,
+    }
+}
+
+/// A rough classification of errors from watching the Pod, so we can tell transient problems from ones that need a human.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WatchErrorKind {
+    /// We aren't allowed to read the Pod.
+    Auth,
+    /// The Pod doesn't exist, or we're looking in the wrong place for it.
+    NotFound,
+    /// A network or apiserver availability problem, which the watcher will retry.
+    Transport,
+    /// The apiserver's response couldn't be understood.
+    Decode,
+    /// Anything else.
+    Other,
+}
+
+impl WatchErrorKind {
+    /// Classify an error from the Pod watch stream.
+    pub fn classify(err: &Error) -> Self {
+        if err.is::<PodMismatch>() {
+            return WatchErrorKind::NotFound;
+        }
+        let kube_err = match err.downcast_ref::<watcher::Error>() {
+            Some(watcher::Error::InitialListFailed(e))
+            | Some(watcher::Error::WatchStartFailed(e))
+            | Some(watcher::Error::WatchFailed(e)) => e,
+            Some(watcher::Error::WatchError(resp)) => return Self::from_status(resp.code),
+            Some(_) => return WatchErrorKind::Other,
+            None => match err.downcast_ref::<kube::Error>() {
+                Some(e) => e,
+                None => return WatchErrorKind::Other,
+            },
+        };
+        match kube_err {
+            kube::Error::Api(resp) => Self::from_status(resp.code),
+            kube::Error::Auth(_) => WatchErrorKind::Auth,
+            kube::Error::HyperError(_) | kube::Error::Service(_) | kube::Error::ReadEvents(_) => {
+                WatchErrorKind::Transport
+            }
+            kube::Error::SerdeError(_) => WatchErrorKind::Decode,
+            _ => WatchErrorKind::Other,
+        }
+    }
+
+    /// Classify an HTTP status code returned by the apiserver.
+    fn from_status(code: u16) -> Self {
+        match code {
+            401 | 403 => WatchErrorKind::Auth,
+            404 => WatchErrorKind::NotFound,
+            408 | 429 | 500..=599 => WatchErrorKind::Transport,
+            _ => WatchErrorKind::Other,
+        }
+    }
+
+    /// Return true if this kind of error is likely to go away on its own.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, WatchErrorKind::Transport | WatchErrorKind::Other)
+    }
+
+    /// A hint for the operator about how to fix this kind of error.
+    pub fn guidance(&self) -> &'static str {
+        match self {
+            WatchErrorKind::Auth => {
+                "Check that the Pod's service account has permission to get, list, and watch pods"
+            }
+            WatchErrorKind::NotFound => {
+                "Check that the Pod's name matches its hostname and that it's in the expected namespace"
+            }
+            WatchErrorKind::Decode => "The apiserver's response was not understood",
+            WatchErrorKind::Transport | WatchErrorKind::Other => "This will be retried",
+        }
+    }
+}
+
 /// The result of watching a Pod.
 enum WatchResult {
-    /// The Pod isn't ready yet.
-    NotReady,
+    /// The Pod isn't ready yet, for the given reason.
+    NotReady(String),
     /// The Pod is ready to execute the main program.
     Ready,
     /// Encountered a k8s API error while watching the Pod.
     ApiError(Error),
-    /// The Pod (probably one of it containers) experienced an error.
-    PodError(Error),
+    /// The named sidecars terminated, though Kubernetes may restart them. There's always at least one.
+    SidecarTerminated(Vec<(String, Error)>),
+    /// The Pod experienced an error that we shouldn't wait out, even if Kubernetes will restart the container.
+    FatalError(Error),
+}
+
+/// What [compute_readiness] found out about a Pod.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadinessReport {
+    /// Whether the Pod is ready for the main program to start.
+    pub ready: bool,
+    /// Each sidecar, in the order of pod.spec.containers.
+    pub sidecars: Vec<SidecarReadiness>,
+    /// Whether the Pod has reported any container statuses at all.
+    pub has_statuses: bool,
+    /// Whether all of the Pod's readiness gates are met.
+    pub gates_met: bool,
+    /// The first main container that terminated or is crash looping, and why.
+    pub broken_main: Option<(String, String)>,
+    /// The sidecars that terminated, and their reasons if Kubernetes gave them.
+    pub terminated: Vec<(String, Option<String>)>,
+    /// How many ready sidecars are enough, if not all of them.
+    pub quorum: Option<usize>,
+}
+
+/// One sidecar's part in a [ReadinessReport].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SidecarReadiness {
+    /// The container's name.
+    pub name: String,
+    /// Whether Kubernetes says the container is ready.
+    pub ready: bool,
+    /// What the container is doing.
+    pub state: SidecarState,
+}
+
+/// What a sidecar is doing, according to its container status.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SidecarState {
+    /// The Pod hasn't reported a status for the container yet.
+    Missing,
+    /// The container hasn't started, with Kubernetes' reason if it gave one.
+    Waiting(Option<String>),
+    /// The container is running.
+    Running,
+    /// The container exited, with Kubernetes' reason if it gave one.
+    Terminated(Option<String>),
+}
+
+impl SidecarState {
+    /// The name of the state in status messages.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SidecarState::Missing => "missing",
+            SidecarState::Waiting(_) => "waiting",
+            SidecarState::Running => "running",
+            SidecarState::Terminated(_) => "terminated",
+        }
+    }
+}
+
+impl ReadinessReport {
+    /// Explain why the Pod isn't ready, for logs and for [WatchEnded].
+    fn not_ready_reason(&self) -> String {
+        let names = |pick: fn(&SidecarReadiness) -> bool| -> Vec<&str> {
+            self.sidecars
+                .iter()
+                .filter(|s| pick(s))
+                .map(|s| s.name.as_str())
+                .collect()
+        };
+        let missing = names(|s| s.state == SidecarState::Missing);
+        let unready = names(|s| s.state != SidecarState::Missing && !s.ready);
+        let ready_count = self.sidecars.iter().filter(|s| s.ready).count();
+        if let Some(quorum) = self.quorum.filter(|&quorum| ready_count < quorum) {
+            format!(
+                "{} sidecars are ready, and {} are needed; sidecars {:?} aren't",
+                ready_count,
+                quorum,
+                names(|s| !s.ready)
+            )
+        } else if !missing.is_empty() {
+            format!("Sidecars {:?} don't have a status yet", missing)
+        } else if !unready.is_empty() {
+            format!("Sidecars {:?} aren't ready", unready)
+        } else if !self.has_statuses {
+            "The Pod hasn't reported any container statuses yet".to_string()
+        } else if !self.gates_met {
+            "The Pod's readiness gates aren't met".to_string()
+        } else {
+            "The Pod isn't ready".to_string()
+        }
+    }
+}
+
+/// Work out whether this Pod is ready for the main process to start, and why or why not, without logging or deciding what to do
+/// about it. It's ready when it has reported its container statuses, all the containers except the main ones and the ones whose
+/// images are ignored are ready, its readiness gates are met, and nothing has terminated. With a quorum, enough of those
+/// containers being ready will do, and the rest can be in any state.
+pub fn compute_readiness(
+    pod: &Pod,
+    main_cont_names: &HashSet<String>,
+    config: &ReadyConfig,
+) -> ReadinessReport {
+    let statuses = pod
+        .status
+        .as_ref()
+        .and_then(|s| s.container_statuses.as_deref())
+        .unwrap_or_default();
+    // Kubernetes sometimes briefly reports statuses for only some of the containers. A sidecar with no status isn't ready.
+    let sidecars: Vec<SidecarReadiness> = pod
+        .spec
+        .iter()
+        .flat_map(|spec| spec.containers.iter())
+        .filter(|c| !main_cont_names.contains(&c.name))
+        .filter(|c| !image_ignored(c, &config.ignore_images))
+        .map(|c| {
+            let status = statuses.iter().find(|s| s.name == c.name);
+            let state = status.and_then(|s| s.state.as_ref());
+            let state = match state {
+                None if status.is_none() => SidecarState::Missing,
+                Some(state) if state.terminated.is_some() => SidecarState::Terminated(
+                    state.terminated.as_ref().and_then(|t| t.reason.clone()),
+                ),
+                Some(state) if state.running.is_some() => SidecarState::Running,
+                state => SidecarState::Waiting(
+                    state
+                        .and_then(|s| s.waiting.as_ref())
+                        .and_then(|w| w.reason.clone()),
+                ),
+            };
+            SidecarReadiness {
+                name: c.name.clone(),
+                ready: status.map_or(false, |s| s.ready),
+                state,
+            }
+        })
+        .collect();
+    let broken_main = broken_main_container(pod, main_cont_names)
+        .map(|(name, reason)| (name.to_string(), reason));
+    let terminated: Vec<_> = sidecars
+        .iter()
+        .filter_map(|s| match &s.state {
+            SidecarState::Terminated(reason) => Some((s.name.clone(), reason.clone())),
+            _ => None,
+        })
+        .collect();
+    let has_statuses = !statuses.is_empty();
+    let gates_met = readiness_gates_met(pod);
+    let sidecars_ready = match config.quorum {
+        Some(quorum) => sidecars.iter().filter(|s| s.ready).count() >= quorum,
+        None => terminated.is_empty() && sidecars.iter().all(|s| s.ready),
+    };
+    let ready = broken_main.is_none() && has_statuses && gates_met && sidecars_ready;
+    ReadinessReport {
+        ready,
+        sidecars,
+        has_statuses,
+        gates_met,
+        broken_main,
+        terminated,
+        quorum: config.quorum,
+    }
+}
+
+/// Return true if the container's image, from the Pod's spec, matches one of the `ignore_images` globs. Container statuses
+/// have an image too, but it's the resolved one, which may not look like what was asked for.
+fn image_ignored(container: &Container, ignore_images: &[String]) -> bool {
+    let image = match container.image.as_deref() {
+        Some(image) => image,
+        None => return false,
+    };
+    let ignored = ignore_images
+        .iter()
+        .any(|pattern| glob_match(pattern, image));
+    if ignored {
+        debug!(
+            container = container.name,
+            image, "Not waiting for container, because of its image"
+        );
+    }
+    ignored
 }
 
 /// Return true if this Pod is ready for the main process to start. That means all the containers except the main one are signaling
 /// ready status.
-fn is_ready(pod: &Pod) -> WatchResult {
+fn is_ready(pod: &Pod, config: &ReadyConfig) -> WatchResult {
     let span = debug_span!("is_ready");
     let _enter = span.enter();
 
-    // The name of the main container in the Pod. For now we pick containers[0].
-    let main_cont_name = match main_cont_name(pod) {
-        Ok(name) => name,
+    // The names of the main containers in the Pod.
+    let main_cont_names = match main_cont_names(pod, config) {
+        Ok(names) => names,
         Err(e) => return WatchResult::ApiError(e),
     };
+    let report = compute_readiness(pod, &main_cont_names, config);
+    debug!(ready = report.ready, terminated = ?report.terminated);
 
-    // Are all of the sidecar containers ready?
-    let ready = &pod
-        .status
+    // If a main container has already terminated or is crash looping, there's nothing left to start, so don't wait forever.
+    if let Some((name, reason)) = &report.broken_main {
+        debug!(container = name, reason, "Main container isn't running");
+        return WatchResult::FatalError(anyhow!(
+            "Main container {} isn't running ({}), so there's nothing to wait for",
+            name,
+            reason
+        ));
+    }
+
+    // With no sidecars there's nothing to wait for, regardless of whether the main container is ready, unless sidecars are
+    // required.
+    if report.sidecars.is_empty() && config.require_sidecars {
+        return WatchResult::FatalError(anyhow!(
+            "The Pod has no sidecars, only main containers {:?}; was a sidecar not injected?",
+            main_cont_names.iter().collect::<BTreeSet<_>>()
+        ));
+    }
+
+    // There's no quorum if there aren't enough sidecars to make one.
+    if let Some(quorum) = config.quorum {
+        if report.sidecars.len() < quorum {
+            return WatchResult::FatalError(anyhow!(
+                "--ready-quorum needs {} sidecars to be ready, but the Pod only has {}",
+                quorum,
+                report.sidecars.len()
+            ));
+        }
+    }
+
+    // With a quorum, the rest of the sidecars don't matter, even if they terminated.
+    if report.ready {
+        return WatchResult::Ready;
+    }
+    if let Some(quorum) = config.quorum {
+        let alive = report
+            .sidecars
+            .iter()
+            .filter(|s| !matches!(s.state, SidecarState::Terminated(_)))
+            .count();
+        if alive >= quorum {
+            return WatchResult::NotReady(report.not_ready_reason());
+        }
+    }
+
+    if !report.terminated.is_empty() {
+        let mut terminated = Vec::new();
+        for (name, reason) in &report.terminated {
+            debug!(container = name, reason, "Sidecar container terminated");
+            let err = match reason {
+                Some(reason) => anyhow!(
+                    "Sidecar container {} terminated prematurely: {}",
+                    name,
+                    reason
+                ),
+                None => anyhow!("Sidecar container {} terminated prematurely", name),
+            };
+            // Running out of memory is worth calling out, because it usually means the sidecar needs a higher memory limit.
+            let oom_killed = reason.as_deref() == Some("OOMKilled");
+            if oom_killed {
+                warn!(container = name, "Sidecar container was OOMKilled");
+                if config.fatal_on_oom {
+                    return WatchResult::FatalError(err);
+                }
+            }
+            terminated.push((name.clone(), err));
+        }
+        return WatchResult::SidecarTerminated(terminated);
+    }
+
+    WatchResult::NotReady(report.not_ready_reason())
+}
+
+/// Return the name of the first main container that has terminated or is in CrashLoopBackOff, along with why.
+fn broken_main_container<'a>(
+    pod: &'a Pod,
+    main_cont_names: &HashSet<String>,
+) -> Option<(&'a str, String)> {
+    pod.status
         .as_ref()
-        .and_then(|s| {
-            s.container_statuses.as_ref().map(|s| {
-                s.iter()
-                    .filter(|s| s.name != main_cont_name)
-                    .all(|s| s.ready)
-            })
-        })
-        .unwrap_or(false);
-    // Are any of the sidecar containers terminated?
-    let error = &pod.status.as_ref().and_then(|pod_stat| {
-        pod_stat.container_statuses.as_ref().map(|cont_stats| {
-            cont_stats
-                .iter()
-                .filter(|cont_stat| cont_stat.name != main_cont_name)
-                .any(|cont_stat| {
-                    cont_stat
-                        .state
-                        .as_ref()
-                        .map(|state| {
-                            if state.terminated.is_some() {
-                                debug!(container = cont_stat.name, "Sidecar container terminated");
-                                true
-                            } else {
-                                false
-                            }
-                        })
-                        .unwrap_or(false)
-                })
+        .and_then(|s| s.container_statuses.as_ref())?
+        .iter()
+        .filter(|s| main_cont_names.contains(&s.name))
+        .find_map(|s| {
+            let state = s.state.as_ref()?;
+            let reason = match (&state.terminated, &state.waiting) {
+                (Some(term), _) => term
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| format!("terminated with exit code {}", term.exit_code)),
+                (None, Some(waiting)) if waiting.reason.as_deref() == Some("CrashLoopBackOff") => {
+                    "CrashLoopBackOff".to_string()
+                }
+                _ => return None,
+            };
+            Some((s.name.as_str(), reason))
         })
-    });
-    debug!(ready, error);
-    match (error, ready) {
-        (Some(true), _) => {
-            WatchResult::PodError(anyhow!("A sidecar container terminated prematurely"))
+}
+
+/// Return true if every one of the Pod's readiness gates has its condition set to "True". Pods without readiness gates trivially
+/// meet them.
+fn readiness_gates_met(pod: &Pod) -> bool {
+    let gates = match pod.spec.as_ref().and_then(|s| s.readiness_gates.as_ref()) {
+        Some(gates) => gates,
+        None => return true,
+    };
+    let conditions = pod
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.as_deref())
+        .unwrap_or_default();
+    gates.iter().all(|gate| {
+        let met = conditions
+            .iter()
+            .any(|c| c.type_ == gate.condition_type && c.status == "True");
+        if !met {
+            debug!(condition = gate.condition_type, "Readiness gate isn't met");
         }
-        (_, false) => WatchResult::NotReady,
-        (_, true) => WatchResult::Ready,
-    }
+        met
+    })
 }
 
-fn main_cont_name(pod: &Pod) -> Result<String, Error> {
-    Ok(pod
-        .spec
+/// Return the names of the main containers: the configured ones, or else the one named by the configured annotation, or else
+/// containers[0].
+pub fn main_cont_names(pod: &Pod, config: &ReadyConfig) -> Result<HashSet<String>, Error> {
+    find_main_cont_names(pod, config).with_context(|| {
+        let count = pod.spec.as_ref().map_or(0, |spec| spec.containers.len());
+        format!(
+            "Unable to identify the main containers in Pod {}, which has {} containers",
+            pod.name_any(),
+            count
+        )
+    })
+}
+
+fn find_main_cont_names(pod: &Pod, config: &ReadyConfig) -> Result<HashSet<String>, Error> {
+    let containers = &pod.spec.as_ref().ok_or(anyhow!("No pod.spec"))?.containers;
+
+    if !config.main_containers.is_empty() {
+        let missing: Vec<&String> = config
+            .main_containers
+            .iter()
+            .filter(|pattern| !containers.iter().any(|c| glob_match(pattern, &c.name)))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Main containers {:?} aren't in pod.spec.containers",
+                missing
+            ));
+        }
+        return Ok(containers
+            .iter()
+            .filter(|c| {
+                config
+                    .main_containers
+                    .iter()
+                    .any(|pattern| glob_match(pattern, &c.name))
+            })
+            .map(|c| c.name.clone())
+            .collect());
+    }
+
+    let annotated = config
+        .main_container_annotation
         .as_ref()
-        .ok_or(anyhow!("No pod.spec"))?
-        .containers
-        .get(0)
-        .ok_or(anyhow!("No pod.spec.containers[0]"))?
-        .name
-        .clone())
+        .and_then(|key| pod.annotations().get(key).map(|name| (key, name)));
+    let name = match annotated {
+        Some((key, name)) => containers
+            .iter()
+            .find(|c| &c.name == name)
+            .map(|c| c.name.clone())
+            .ok_or(anyhow!(
+                "Annotation {} names container {}, which isn't in pod.spec.containers",
+                key,
+                name
+            ))?,
+        None => containers
+            .get(0)
+            .ok_or(anyhow!("No pod.spec.containers[0]"))?
+            .name
+            .clone(),
+    };
+    Ok(HashSet::from([name]))
 }
 
 #[cfg(test)]
@@ -175,10 +1226,20 @@ mod tests {
 
     use super::*;
 
+    /// Run [filter_ready] without keeping the reason the Pod isn't ready.
+    async fn check(
+        pod: Result<Option<Pod>, Error>,
+        config: &ReadyConfig,
+    ) -> Option<Result<Pod, Error>> {
+        filter_ready(pod, config, &LastReason::default()).await
+    }
+
     #[tokio::test]
     async fn check_ready() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+
         // Pass in an error, it's not ready.
-        assert!(filter_ready(Err(anyhow!["foo"])).await.is_none());
+        assert!(check(Err(anyhow!["foo"]), &config).await.is_none());
 
         // A pod where only the main container is ready.
         let pod = object! {
@@ -194,7 +1255,10 @@ mod tests {
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         assert_eq!(
-            filter_ready(Ok(Some(pod.clone()))).await.unwrap().unwrap(),
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
             pod
         );
 
@@ -212,7 +1276,10 @@ mod tests {
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         assert_eq!(
-            filter_ready(Ok(Some(pod.clone()))).await.unwrap().unwrap(),
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
             pod
         );
 
@@ -236,7 +1303,10 @@ mod tests {
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         assert_eq!(
-            filter_ready(Ok(Some(pod.clone()))).await.unwrap().unwrap(),
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
             pod
         );
 
@@ -259,7 +1329,7 @@ mod tests {
             }
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
-        assert!(filter_ready(Ok(Some(pod.clone()))).await.is_none());
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
 
         // A pod with one ready sidecar, one not-ready.
         let pod = object! {
@@ -282,7 +1352,7 @@ mod tests {
             }
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
-        assert!(filter_ready(Ok(Some(pod.clone()))).await.is_none());
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
 
         // A pod with two ready sidecars.
         let pod = object! {
@@ -306,7 +1376,10 @@ mod tests {
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         assert_eq!(
-            filter_ready(Ok(Some(pod.clone()))).await.unwrap().unwrap(),
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
             pod
         );
 
@@ -330,7 +1403,10 @@ mod tests {
             }
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
-        assert!(filter_ready(Ok(Some(pod.clone()))).await.unwrap().is_err());
+        assert!(check(Ok(Some(pod.clone())), &config)
+            .await
+            .unwrap()
+            .is_err());
 
         // A pod with a sidecar that failed and will be restarted.
         let pod = object! {
@@ -351,8 +1427,952 @@ mod tests {
             }
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
-        assert!(filter_ready(Ok(Some(pod.clone()))).await.is_none());
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn check_ready_on_failure() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+        let pod = |exit_code: i32| -> Result<Pod, Error> {
+            let pod = object! {
+                apiVersion: "v1",
+                kind: "Pod",
+                metadata: { name: "pod1" },
+                spec: {
+                    containers: [
+                        { name: "cont1" },
+                        { name: "cont2" },
+                    ],
+                    restartPolicy: "OnFailure"
+                },
+                status: {
+                    containerStatuses: [
+                        { name: "cont1", ready: true },
+                        { name: "cont2", state: { terminated: { exitCode: exit_code } }  },
+                    ]
+                }
+            };
+            Ok(serde_json::from_str(pod.dump().as_str())?)
+        };
+
+        // The sidecar exited successfully, so under OnFailure it won't be restarted, and it'll never be ready.
+        assert!(check(Ok(Some(pod(0)?)), &config).await.unwrap().is_err());
+
+        // The sidecar failed, so it will be restarted; keep waiting.
+        assert!(check(Ok(Some(pod(1)?)), &config).await.is_none());
+
+        // Each terminated sidecar's own exit code decides whether it's restarted, and one that won't be is enough to give up.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                    { name: "cont3" },
+                ],
+                restartPolicy: "OnFailure"
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: true },
+                    { name: "cont2", state: { terminated: { exitCode: 1 } } },
+                    { name: "cont3", state: { terminated: { exitCode: 0 } } },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(will_restart_sidecar(&pod, "cont2"));
+        assert!(!will_restart_sidecar(&pod, "cont3"));
+        let result = check(Ok(Some(pod)), &config).await.unwrap();
+        assert!(result.unwrap_err().to_string().contains("cont3"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_ignore_image() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1", image: "app:1" },
+                    { name: "cont2", image: "envoyproxy/envoy:v1.26" },
+                    { name: "vault-agent-x7", image: "hashicorp/vault:1.13" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                    { name: "vault-agent-x7", ready: false },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+
+        // The vault agent isn't ready, so the Pod isn't either.
+        assert!(check(Ok(Some(pod.clone())), &ReadyConfig::default())
+            .await
+            .is_none());
+
+        // Unless it's ignored by its image, whatever it's named.
+        let config = ReadyConfig {
+            ignore_images: vec!["*vault*".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
+            pod
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_quorum() -> Result<(), Error> {
+        let make_pod = |pool2_ready: bool| -> Result<Pod, Error> {
+            let pod = object! {
+                apiVersion: "v1",
+                kind: "Pod",
+                metadata: { name: "pod1" },
+                spec: {
+                    containers: [
+                        { name: "cont1" },
+                        { name: "pool1" },
+                        { name: "pool2" },
+                        { name: "pool3" },
+                    ],
+                    restartPolicy: "Never"
+                },
+                status: {
+                    containerStatuses: [
+                        { name: "cont1", ready: false },
+                        { name: "pool1", ready: true, state: { running: {} } },
+                        { name: "pool2", ready: pool2_ready, state: { running: {} } },
+                        { name: "pool3", ready: false, state: { terminated: { exitCode: 1 } } },
+                    ]
+                }
+            };
+            Ok(serde_json::from_str(pod.dump().as_str())?)
+        };
+        let pod_unready = make_pod(false)?;
+        let pod = make_pod(true)?;
+        let config = |quorum| ReadyConfig {
+            quorum: Some(quorum),
+            ..Default::default()
+        };
+
+        // Two of the three sidecars are ready, which meets a quorum of two, even though the third terminated.
+        for quorum in [1, 2] {
+            assert_eq!(
+                check(Ok(Some(pod.clone())), &config(quorum))
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                pod
+            );
+        }
+
+        // A quorum of three can't be met, because the terminated sidecar won't be restarted.
+        assert!(check(Ok(Some(pod.clone())), &config(3))
+            .await
+            .unwrap()
+            .is_err());
+
+        // There are only three sidecars, so a quorum of four can never be met.
+        let err = check(Ok(Some(pod.clone())), &config(4))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("only has 3"));
+
+        // With only one sidecar ready, a quorum of two isn't met yet, but it still can be, so keep waiting.
+        assert!(check(Ok(Some(pod_unready.clone())), &config(2))
+            .await
+            .is_none());
+
+        // Without a quorum, the terminated sidecar is an error.
+        assert!(check(Ok(Some(pod.clone())), &ReadyConfig::default())
+            .await
+            .unwrap()
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn quorum_reason() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }, { name: "pool1" }, { name: "pool2" }]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "pool1", ready: true },
+                    { name: "pool2", ready: false },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let main = HashSet::from(["cont1".to_string()]);
+        let config = ReadyConfig {
+            quorum: Some(2),
+            ..Default::default()
+        };
+        let report = compute_readiness(&pod, &main, &config);
+        assert!(!report.ready);
+        assert_eq!(
+            report.not_ready_reason(),
+            r#"1 sidecars are ready, and 2 are needed; sidecars ["pool2"] aren't"#
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_no_sidecars() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+
+        // A single-container pod is ready even if the main container isn't.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }]
+            },
+            status: {
+                containerStatuses: [{ name: "cont1", ready: false }]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert_eq!(
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
+            pod
+        );
+
+        // A single-container pod with no statuses yet isn't ready.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }]
+            },
+            status: {}
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
+
+        // Nor is one with an empty list of statuses.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }]
+            },
+            status: {
+                containerStatuses: []
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_annotated_main() -> Result<(), Error> {
+        let config = ReadyConfig {
+            main_container_annotation: Some("proa.io/main".to_string()),
+            ..Default::default()
+        };
+
+        // The annotation names the second container as main, so the first one is a sidecar that isn't ready.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1", annotations: { "proa.io/main": "cont2" } },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
+
+        // Without the annotation, containers[0] is main, so the ready second container means the Pod is ready.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert_eq!(
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
+            pod
+        );
+
+        // An annotation naming a container that doesn't exist is an error.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1", annotations: { "proa.io/main": "cont3" } },
+            spec: {
+                containers: [{ name: "cont1" }]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let err = main_cont_names(&pod, &config).unwrap_err();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("Pod pod1, which has 1 containers"), "{}", msg);
+        assert!(msg.contains("cont3"), "{}", msg);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_multiple_main() -> Result<(), Error> {
+        let config = ReadyConfig {
+            main_containers: vec!["cont1".to_string(), "cont2".to_string()],
+            ..Default::default()
+        };
+
+        // Two main containers that aren't ready, and a ready sidecar.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                    { name: "cont3" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: false },
+                    { name: "cont3", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert_eq!(
+            check(Ok(Some(pod.clone())), &config)
+                .await
+                .unwrap()
+                .unwrap(),
+            pod
+        );
+        assert_eq!(
+            main_cont_names(&pod, &config)?,
+            HashSet::from(["cont1".to_string(), "cont2".to_string()])
+        );
+
+        // A main container that isn't in the Pod is an error.
+        let config = ReadyConfig {
+            main_containers: vec!["cont4".to_string()],
+            ..Default::default()
+        };
+        assert!(main_cont_names(&pod, &config).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_readiness_gates() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+
+        // The sidecar is ready, but the readiness gate's condition isn't met yet.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }, { name: "cont2" }],
+                readinessGates: [{ conditionType: "example.com/ready" }]
+            },
+            status: {
+                conditions: [{ "type": "example.com/ready", status: "False" }],
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod)), &config).await.is_none());
+
+        // Now it is.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }, { name: "cont2" }],
+                readinessGates: [{ conditionType: "example.com/ready" }]
+            },
+            status: {
+                conditions: [{ "type": "example.com/ready", status: "True" }],
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod)), &config).await.unwrap().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_partial_statuses() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+
+        // Three containers, but only two statuses so far. The sidecar with no status isn't ready yet.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                    { name: "cont3" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod)), &config).await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_oom_killed() -> Result<(), Error> {
+        // A pod with a sidecar that was OOMKilled and will be restarted.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                ],
+                restartPolicy: "OnFailure"
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: true },
+                    { name: "cont2", state: { terminated: { exitCode: 137, reason: "OOMKilled" } }  },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        match is_ready(&pod, &ReadyConfig::default()) {
+            WatchResult::SidecarTerminated(terminated) => {
+                assert!(terminated[0].1.to_string().contains("OOMKilled"))
+            }
+            _ => panic!("expected SidecarTerminated"),
+        }
+
+        // By default, keep waiting for Kubernetes to restart it.
+        let config = ReadyConfig::default();
+        assert!(check(Ok(Some(pod.clone())), &config).await.is_none());
+
+        // With fatal_on_oom, give up right away.
+        let config = ReadyConfig {
+            fatal_on_oom: true,
+            ..Default::default()
+        };
+        let result = check(Ok(Some(pod.clone())), &config).await.unwrap();
+        assert!(result.unwrap_err().to_string().contains("cont2"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_ready_main_terminated() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+
+        // The main container crashed while the sidecar was starting up. Even though Kubernetes may restart it, give up.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false, state: { terminated: { exitCode: 1, reason: "Error" } } },
+                    { name: "cont2", ready: false },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let err = check(Ok(Some(pod)), &config).await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("cont1"), "{}", err);
+
+        // A main container in CrashLoopBackOff is just as broken.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false, state: { waiting: { reason: "CrashLoopBackOff" } } },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod)), &config).await.unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn readiness_report() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                    { name: "cont3" },
+                    { name: "cont4" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false, state: { running: {} } },
+                    { name: "cont2", ready: true, state: { running: {} } },
+                    { name: "cont3", ready: false, state: { waiting: { reason: "ImagePullBackOff" } } },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let main = HashSet::from(["cont1".to_string()]);
+        let report = compute_readiness(&pod, &main, &ReadyConfig::default());
+        assert_eq!(
+            report,
+            ReadinessReport {
+                ready: false,
+                sidecars: vec![
+                    SidecarReadiness {
+                        name: "cont2".to_string(),
+                        ready: true,
+                        state: SidecarState::Running,
+                    },
+                    SidecarReadiness {
+                        name: "cont3".to_string(),
+                        ready: false,
+                        state: SidecarState::Waiting(Some("ImagePullBackOff".to_string())),
+                    },
+                    SidecarReadiness {
+                        name: "cont4".to_string(),
+                        ready: false,
+                        state: SidecarState::Missing,
+                    },
+                ],
+                has_statuses: true,
+                gates_met: true,
+                broken_main: None,
+                terminated: Vec::new(),
+                quorum: None,
+            }
+        );
+        assert_eq!(
+            report.not_ready_reason(),
+            "Sidecars [\"cont4\"] don't have a status yet"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_require_sidecars() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }]
+            },
+            status: {
+                containerStatuses: [{ name: "cont1", ready: false }]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        // Without the flag, a Pod with no sidecars is ready straight away.
+        assert!(check(Ok(Some(pod.clone())), &ReadyConfig::default())
+            .await
+            .unwrap()
+            .is_ok());
+
+        let config = ReadyConfig {
+            require_sidecars: true,
+            ..Default::default()
+        };
+        let err = check(Ok(Some(pod)), &config).await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("no sidecars"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_terminating() -> Result<(), Error> {
+        let config = ReadyConfig::default();
+
+        // The Pod was deleted before the sidecar was ready, so it never will be.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1", deletionTimestamp: "2023-06-01T12:00:00Z" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: false },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let err = check(Ok(Some(pod)), &config).await.unwrap().unwrap_err();
+        assert!(err.is::<PodTerminating>(), "{}", err);
+        assert!(err.to_string().contains("2023-06-01T12:00:00"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ready_files() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("proa-test-ready-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let files = [ReadyFile {
+            path: path.clone(),
+            contents: Some("ok".to_string()),
+        }];
+        let last_reason = LastReason::default();
+        let wait = wait_for_ready_files(&files, &last_reason);
+        tokio::pin!(wait);
+
+        // Not there yet.
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut wait)
+            .await
+            .is_err());
+        assert!(last_reason.take().unwrap().contains("ready file"));
+        // There, but not holding the right contents.
+        std::fs::write(&path, "starting")?;
+        assert!(tokio::time::timeout(Duration::from_millis(400), &mut wait)
+            .await
+            .is_err());
+        std::fs::write(&path, "ok\n")?;
+        tokio::time::timeout(Duration::from_secs(1), &mut wait).await?;
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_fail_fast() -> Result<(), Error> {
+        let config = ReadyConfig {
+            fail_fast: true,
+            ..Default::default()
+        };
+
+        // Transient errors are still retried.
+        assert!(check(Err(anyhow!["foo"]), &config).await.is_none());
+
+        // Errors that need a human aren't.
+        let err = Error::new(kube::Error::Api(kube::core::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "forbidden".to_string(),
+            reason: "Forbidden".to_string(),
+            code: 403,
+        }));
+        assert!(check(Err(err), &config).await.unwrap().is_err());
+
+        // A configured main container that doesn't exist.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }, { name: "cont2" }]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let missing_main = ReadyConfig {
+            main_containers: vec!["cont3".to_string()],
+            ..config.clone()
+        };
+        assert!(check(Ok(Some(pod)), &missing_main).await.unwrap().is_err());
+
+        // A sidecar that failed and would be restarted.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [{ name: "cont1" }, { name: "cont2" }],
+                restartPolicy: "OnFailure"
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: true },
+                    { name: "cont2", state: { terminated: { exitCode: 1 } }  },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(check(Ok(Some(pod)), &config).await.unwrap().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_grace_period() -> Result<(), Error> {
+        fn pod_with_grace(seconds: i64) -> Result<Pod, Error> {
+            let pod = object! {
+                apiVersion: "v1",
+                kind: "Pod",
+                metadata: { name: "pod1" },
+                spec: {
+                    containers: [{ name: "cont1" }],
+                    terminationGracePeriodSeconds: seconds,
+                }
+            };
+            Ok(serde_json::from_str(pod.dump().as_str())?)
+        }
+
+        assert_eq!(grace_period(None), Duration::from_secs(30));
+        assert_eq!(
+            grace_period(Some(&pod_with_grace(45)?)),
+            Duration::from_secs(45)
+        );
+        assert_eq!(
+            grace_period(Some(&pod_with_grace(-5)?)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            grace_period(Some(&pod_with_grace(i64::MAX)?)),
+            Duration::from_secs(MAX_GRACE_PERIOD)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pod_events() -> Result<(), Error> {
+        let pod: Pod = serde_json::from_str(
+            object! {
+                apiVersion: "v1",
+                kind: "Pod",
+                metadata: { name: "pod1" },
+            }
+            .dump()
+            .as_str(),
+        )?;
+
+        assert!(pod_from_event(watcher::Event::Applied(pod.clone())).is_some());
+        assert!(pod_from_event(watcher::Event::Deleted(pod.clone())).is_none());
+        // A resync that finds the Pod isn't a deletion.
+        assert_eq!(
+            pod_from_event(watcher::Event::Restarted(vec![pod.clone()])).map(|p| p.name_any()),
+            Some("pod1".to_string())
+        );
+        assert!(pod_from_event(watcher::Event::Restarted(vec![])).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn slim() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: {
+                name: "pod1",
+                annotations: {
+                    "kubectl.kubernetes.io/last-applied-configuration": "{\"big\":true}",
+                    "proa/main": "cont1",
+                },
+                managedFields: [
+                    { manager: "kubelet", operation: "Update", fieldsType: "FieldsV1", fieldsV1: {} },
+                ],
+            },
+            spec: {
+                containers: [{ name: "cont1" }]
+            },
+            status: {
+                containerStatuses: [{ name: "cont1", ready: true }]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let slimmed = slim_pod(pod.clone());
+        assert_eq!(slimmed.metadata.managed_fields, None);
+        assert_eq!(
+            slimmed.annotations().keys().collect::<Vec<_>>(),
+            vec!["proa/main"]
+        );
+        // Everything proa does look at is left alone.
+        assert_eq!(slimmed.spec, pod.spec);
+        assert_eq!(slimmed.status, pod.status);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pod_name() {
+        assert_eq!(pod_name_from_hostname("pod1", false), "pod1");
+        assert_eq!(
+            pod_name_from_hostname("pod1.svc.ns.svc.cluster.local", false),
+            "pod1"
+        );
+        assert_eq!(pod_name_from_hostname("pod.1", true), "pod.1");
+
+        // A configured name beats the hostname.
+        let config = ReadyConfig {
+            pod_name: Some("configured".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(my_pod_name(&config), "configured");
+    }
+
+    #[tokio::test]
+    async fn watch_ended() {
+        let events = futures::stream::empty::<Result<Option<Pod>, Error>>();
+        let err = wait_for_ready_from(events, &ReadyConfig::default())
+            .await
+            .unwrap_err();
+        assert!(err.is::<WatchEnded>());
+    }
+
+    #[tokio::test]
+    async fn watch_ended_with_reason() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "envoy" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: true },
+                    { name: "envoy", ready: false },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let events = futures::stream::iter([Ok(Some(pod))]);
+        let err = wait_for_ready_from(events, &ReadyConfig::default())
+            .await
+            .unwrap_err();
+        assert!(err.is::<WatchEnded>());
+        assert!(err.to_string().contains("envoy"), "{}", err);
+
+        // The reason survives a timeout too.
+        let err = ReadyTimeout {
+            timeout: Duration::from_secs(5),
+            last_reason: Some("Sidecars [\"envoy\"] aren't ready".to_string()),
+        };
+        assert!(err
+            .to_string()
+            .ends_with("(last seen: Sidecars [\"envoy\"] aren't ready)"));
+        Ok(())
+    }
+
+    #[test]
+    fn classify_errors() {
+        fn api_error(code: u16) -> Error {
+            anyhow!(watcher::Error::WatchError(kube::core::ErrorResponse {
+                status: "Failure".to_string(),
+                message: "oops".to_string(),
+                reason: "Oops".to_string(),
+                code,
+            }))
+        }
+
+        assert_eq!(
+            WatchErrorKind::classify(&api_error(403)),
+            WatchErrorKind::Auth
+        );
+        assert_eq!(
+            WatchErrorKind::classify(&api_error(404)),
+            WatchErrorKind::NotFound
+        );
+        assert_eq!(
+            WatchErrorKind::classify(&api_error(503)),
+            WatchErrorKind::Transport
+        );
+        assert_eq!(
+            WatchErrorKind::classify(&anyhow!("foo")),
+            WatchErrorKind::Other
+        );
+        assert!(!WatchErrorKind::Auth.is_retriable());
+        assert!(WatchErrorKind::Transport.is_retriable());
+    }
 }
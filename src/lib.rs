@@ -0,0 +1,28 @@
+//! Manage the lifecycle of Kubernetes sidecar containers.
+//!
+//! This is the library behind the `proa` executable. It can be used to embed proa's logic in other programs: wait for the
+//! sidecars in a Pod to be ready with [`k8s::wait_for_ready`], run a program with [`exec::run`], and shut the sidecars down with
+//! [`shutdown::shutdown`].
+
+// Killing processes relies on Unix signals.
+#[cfg(all(feature = "kill", not(unix)))]
+compile_error!("The kill feature is only supported on Unix");
+
+/// Command line arguments.
+pub mod config;
+/// Waiting for dependencies outside the Pod.
+pub mod dependencies;
+/// Waiting for a sidecar's connections to drain.
+pub mod drain;
+/// Running the main program.
+pub mod exec;
+/// Watching our own Pod in Kubernetes.
+pub mod k8s;
+/// Checking that proa can do its job, before it's needed.
+pub mod preflight;
+/// Shutting down the sidecars.
+pub mod shutdown;
+/// Reporting proa's state to other programs.
+pub mod status;
+/// Stream combinators.
+pub mod stream;
@@ -1,35 +1,383 @@
+use std::cell::Cell;
+use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-use anyhow::Error;
+use anyhow::{anyhow, Context, Error};
 use clap::Parser;
-use config::Cli;
-use tracing::{info, warn};
-
-mod config;
-mod exec;
-mod k8s;
-mod shutdown;
-mod stream;
+use k8s_openapi::api::core::v1::Pod;
+use kube::ResourceExt;
+use proa::config::{Cli, Mode};
+use proa::{dependencies, exec, k8s, preflight, shutdown, status};
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<ExitCode, Error> {
     let cli = Cli::parse();
+    let start = Instant::now();
+    let deadline = cli
+        .deadline
+        .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
 
-    tracing_subscriber::fmt().json().init();
+    // Writing to the file happens on another thread, so a slow volume can't hold up proa. The guard flushes it when main returns.
+    let (log_file, _log_guard) = match &cli.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Unable to open log file {}", path.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::fmt()
+        .json()
+        .with_max_level(Level::DEBUG)
+        .with_writer(std::io::stdout.and(log_file))
+        .finish()
+        .with(cli.log_filter())
+        .init();
     info!("Starting up.");
+    debug!(
+        config = ?cli.redacted(),
+        kill = cfg!(feature = "kill"),
+        kube_exec = cfg!(feature = "kube-exec"),
+        grpc = cfg!(feature = "grpc"),
+        "Configuration"
+    );
+    shutdown::shutdown_stages(&cli.shutdown_after)?;
+
+    // Only running a command and the shutdown subcommand shut the sidecars down, so the other modes don't need a way to.
+    match cli.mode {
+        Some(Mode::Shutdown) => {
+            cli.check_shutdown()?;
+            return shutdown_only(&cli).await;
+        }
+        Some(Mode::Wait {
+            summary,
+            ref signal_file,
+        }) => return wait_only(&cli, summary, signal_file.as_deref()).await,
+        Some(Mode::Preflight) => {
+            preflight::preflight(&preflight::PreflightConfig::from(&cli)).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => cli.check_shutdown()?,
+    }
 
-    let wait_result = k8s::wait_for_ready().await;
+    // If the Pod is deleted while the sidecars are still starting up, we still need to shut them down, so handle SIGTERM until the
+    // command takes over handling it.
+    let mut sigterm = exec::Termination::listen()?;
+    let ready_config = k8s::ReadyConfig::from(&cli);
+    if let Some(port) = cli.status_port {
+        let status = ready_config.status.clone();
+        tokio::spawn(async move {
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            if let Err(err) = status::serve(addr, status).await {
+                warn!(err = format!("{:#}", err), "Status endpoint stopped");
+            }
+        });
+    }
+    #[cfg(unix)]
+    if let Some(path) = cli.status_socket.clone() {
+        let status = ready_config.status.clone();
+        tokio::spawn(async move {
+            if let Err(err) = status::serve_socket(&path, status).await {
+                warn!(err = format!("{:#}", err), "Status socket stopped");
+            }
+        });
+    }
+    // How long each phase took, for the exit log.
+    let ready_time = Cell::new(None);
+    let command_time = Cell::new(None);
+    // Whether the drain requests went out early, because of --drain-with-main.
+    let drained = Cell::new(false);
+    let run = async {
+        let wait_result = tokio::select! {
+            result = k8s::wait_for_ready(&ready_config) => Some(result),
+            _ = sigterm.recv() => None,
+            _ = exec::deadline_passed(deadline) => Some(Err(exec::DeadlineExceeded.into())),
+        };
+        ready_time.set(Some(start.elapsed()));
 
-    // If sidecar startup was successful, then keep a copy of our Pod for later, and also run the wrapped program.
-    let (maybe_pod, status) = match wait_result {
-        Ok(_) => (wait_result.ok(), exec::run(&cli.command, &cli.args)),
-        Err(e) => (None, Err(e)),
+        // If sidecar startup was successful, then keep a copy of our Pod for later, and also run the wrapped program once any
+        // other dependencies are available. If the sidecars were just slow, we may have been asked to run the program anyway;
+        // but any other error means something is actually wrong.
+        match wait_result {
+            None => {
+                warn!("Received SIGTERM while waiting for the Pod to be ready; shutting down");
+                (None, Ok(exec::EXIT_TERMINATED))
+            }
+            Some(Ok(pod)) => {
+                let status = run_command(
+                    &cli,
+                    Some(&pod),
+                    &mut sigterm,
+                    &ready_config.status,
+                    deadline,
+                    &command_time,
+                    &drained,
+                )
+                .await;
+                (Some(pod), status)
+            }
+            Some(Err(e)) if e.is::<exec::DeadlineExceeded>() => {
+                warn!("The deadline passed while waiting for the Pod to be ready; shutting down");
+                (None, Ok(exec::EXIT_DEADLINE))
+            }
+            Some(Err(e)) if e.is::<k8s::PodTerminating>() => {
+                warn!(
+                    err = e.to_string(),
+                    "Shutting down without running the command"
+                );
+                (None, Ok(exec::EXIT_TERMINATED))
+            }
+            Some(Err(e)) if cli.run_on_timeout && e.is::<k8s::ReadyTimeout>() => {
+                warn!(err = e.to_string(), "Running the command anyway");
+                (
+                    None,
+                    run_command(
+                        &cli,
+                        None,
+                        &mut sigterm,
+                        &ready_config.status,
+                        deadline,
+                        &command_time,
+                        &drained,
+                    )
+                    .await,
+                )
+            }
+            Some(Err(e)) => {
+                error!(
+                    err = format!("{:#}", e),
+                    "The Pod didn't become ready; shutting down without running the command"
+                );
+                (None, Ok(exec::EXIT_NOT_READY))
+            }
+        }
     };
+    // With --shutdown-signal, the sidecars can be shut down on request while that goes on.
+    #[cfg(unix)]
+    let ((maybe_pod, mut status), already_shut_down) = match cli.shutdown_signal {
+        Some(signal) => shutdown_on_signal(&cli, signal, run).await?,
+        None => (run.await, false),
+    };
+    #[cfg(not(unix))]
+    let ((maybe_pod, mut status), already_shut_down) = (run.await, false);
 
-    if let Err(err) = shutdown::shutdown(cli, maybe_pod).await {
-        warn!(err = err.to_string(), "Shutdown problem");
+    ready_config.status.set_phase(status::Phase::ShuttingDown);
+    // Only the command's own failure counts, not a failure to get it started.
+    let failed = command_time.get().is_some() && matches!(status, Ok(code) if code != 0);
+    let shutdown_start = Instant::now();
+    if already_shut_down {
+        info!("The sidecars were already shut down because of --shutdown-signal");
+    } else if cli.no_shutdown {
+        info!("Leaving the sidecars running because of --no-shutdown");
+    } else if cli.keep_sidecars_on_failure && failed {
+        warn!(
+            ?status,
+            "The command failed; leaving the sidecars running because of --keep-sidecars-on-failure"
+        );
+    } else {
+        let mut shutdown_config = shutdown::ShutdownConfig::from(&cli);
+        shutdown_config.drained = drained.get();
+        let shutdown = shutdown::shutdown(shutdown_config, maybe_pod);
+        tokio::select! {
+            result = shutdown => {
+                if let Err(err) = result {
+                    warn!(err = err.to_string(), "Shutdown problem");
+                    // A failed command says more about what went wrong, so only a successful one's status is replaced.
+                    if matches!(status, Ok(0)) {
+                        status = Ok(exec::EXIT_SHUTDOWN_FAILED);
+                    }
+                }
+            }
+            _ = exec::deadline_passed(deadline) => {
+                warn!("The deadline passed while shutting down the sidecars");
+                status = Ok(exec::EXIT_DEADLINE);
+            }
+        }
     }
 
-    info!(?status, "Exiting.");
+    info!(
+        ?status,
+        total_ms = millis(start.elapsed()),
+        ready_ms = ready_time.get().map(millis),
+        command_ms = command_time.get().map(millis),
+        shutdown_ms = millis(shutdown_start.elapsed()),
+        "Exiting."
+    );
     status.map(|c| c.into())
 }
+
+/// Convert a duration to whole milliseconds, for logging.
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Wait for any other dependencies, and then run the wrapped program. If we're asked to terminate while waiting, don't run it.
+/// How long the program ran goes in `command_time`, and whether the drain requests were sent while it exited goes in `drained`.
+async fn run_command(
+    cli: &Cli,
+    maybe_pod: Option<&Pod>,
+    sigterm: &mut exec::Termination,
+    status: &status::Status,
+    deadline: Option<tokio::time::Instant>,
+    command_time: &Cell<Option<Duration>>,
+    drained: &Cell<bool>,
+) -> Result<u8, Error> {
+    let mut opts = exec::RunOptions::new(cli, k8s::grace_period(maybe_pod));
+    opts.deadline = deadline;
+    let deps = dependencies::Dependencies::from(cli);
+    tokio::select! {
+        result = dependencies::wait_for_dependencies(&deps) => result?,
+        _ = sigterm.recv() => {
+            warn!("Received SIGTERM while waiting for dependencies; shutting down");
+            return Ok(exec::EXIT_TERMINATED);
+        }
+        _ = exec::deadline_passed(deadline) => {
+            warn!("The deadline passed while waiting for dependencies; shutting down");
+            return Ok(exec::EXIT_DEADLINE);
+        }
+    }
+    status.set_phase(status::Phase::Running);
+    let command = cli
+        .command
+        .as_ref()
+        .ok_or_else(|| anyhow!("No command to run"))?;
+    let command_start = Instant::now();
+    let on_terminate = async {
+        if cli.drain_with_main {
+            let config = shutdown::ShutdownConfig::from(cli);
+            shutdown::drain_with_main(config, maybe_pod, opts.grace_period).await;
+            drained.set(true);
+        }
+    };
+    let exit = exec::run_to_exit(command, &cli.args, &opts, on_terminate).await;
+    command_time.set(Some(command_start.elapsed()));
+    let exit = exit?;
+    match &exit {
+        exec::ExitReason::Exited(code) => info!(code, "The command exited"),
+        exec::ExitReason::Signaled(_) => warn!(
+            signal = exit.signal_name(),
+            code = exit.code(),
+            "The command was killed by a signal"
+        ),
+        exec::ExitReason::SpawnFailed(code) => warn!(code, "The command couldn't be started"),
+        exec::ExitReason::DeadlineExceeded => {
+            warn!(
+                code = exit.code(),
+                "The command was stopped at the deadline"
+            )
+        }
+    }
+    if let Some(key) = &cli.write_exit_annotation {
+        let value = exit.to_json().to_string();
+        match k8s::annotate_my_pod(key, &value, &k8s::ReadyConfig::from(cli)).await {
+            Ok(()) => debug!(key, value, "Recorded the exit in an annotation"),
+            Err(err) => warn!(err = format!("{:#}", err), "Unable to record the exit"),
+        }
+    }
+    Ok(exit.code())
+}
+
+/// Drive `run` to completion, and if the signal arrives in the meantime, shut down the sidecars while it keeps going. Return
+/// what `run` returned, and whether the sidecars were shut down.
+#[cfg(unix)]
+async fn shutdown_on_signal<T>(
+    cli: &Cli,
+    signal: nix::sys::signal::Signal,
+    run: impl Future<Output = T>,
+) -> Result<(T, bool), Error> {
+    use tokio::signal::unix::{self, SignalKind};
+
+    // Listening also keeps the signal from killing proa, which is what most signals do by default.
+    let mut signals = unix::signal(SignalKind::from_raw(signal as i32))?;
+    let started = Cell::new(false);
+    let on_signal = async {
+        if signals.recv().await.is_none() {
+            return std::future::pending().await;
+        }
+        started.set(true);
+        info!(?signal, "Shutting down the sidecars on request");
+        let maybe_pod = k8s::get_my_pod(&k8s::ReadyConfig::from(cli)).await.ok();
+        if let Err(err) = shutdown::shutdown(shutdown::ShutdownConfig::from(cli), maybe_pod).await {
+            warn!(err = err.to_string(), "Shutdown problem");
+        }
+    };
+    tokio::pin!(run, on_signal);
+    let result = tokio::select! {
+        result = &mut run => {
+            // Let a shutdown that already started finish.
+            if started.get() {
+                on_signal.await;
+            }
+            result
+        }
+        _ = &mut on_signal => run.await,
+    };
+    Ok((result, started.get()))
+}
+
+/// Skip running a command, and just shut down the sidecars. This is for running proa as a preStop hook.
+async fn shutdown_only(cli: &Cli) -> Result<ExitCode, Error> {
+    let maybe_pod = match k8s::get_my_pod(&k8s::ReadyConfig::from(cli)).await {
+        Ok(pod) => Some(pod),
+        Err(err) => {
+            warn!(err = err.to_string(), "Unable to fetch Pod");
+            None
+        }
+    };
+    if let Err(err) = shutdown::shutdown(shutdown::ShutdownConfig::from(cli), maybe_pod).await {
+        error!(
+            err = format!("{:#}", err),
+            "Unable to shut down the sidecars"
+        );
+        return Ok(ExitCode::from(exec::EXIT_SHUTDOWN_FAILED));
+    }
+
+    info!("Exiting.");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Wait for the sidecars to be ready, and exit without running a command. This is for running proa as a gate in front of the
+/// main program, when proa can't wrap it. If there's a signal file, write it once they're ready, for the main program to wait on.
+async fn wait_only(
+    cli: &Cli,
+    summary: bool,
+    signal_file: Option<&Path>,
+) -> Result<ExitCode, Error> {
+    let start = Instant::now();
+    let result = k8s::wait_for_ready(&k8s::ReadyConfig::from(cli)).await;
+
+    if summary {
+        let summary = serde_json::json!({
+            "ready": result.is_ok(),
+            "pod": result.as_ref().ok().map(|pod| pod.name_any()),
+            "elapsedMillis": millis(start.elapsed()),
+            "error": result.as_ref().err().map(|err| err.to_string()),
+        });
+        println!("{}", summary);
+    }
+    if let Err(err) = result {
+        error!(err = format!("{:#}", err), "The Pod didn't become ready");
+        return Ok(ExitCode::from(exec::EXIT_NOT_READY));
+    }
+    if let Some(path) = signal_file {
+        tokio::fs::write(path, "ready\n")
+            .await
+            .with_context(|| format!("Unable to write the signal file {}", path.display()))?;
+        info!(path = %path.display(), "Wrote the signal file");
+    }
+
+    info!("Exiting.");
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,265 @@
+use anyhow::{anyhow, Error};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::PostParams;
+use kube::{Api, Client};
+use tracing::{error, info, warn};
+
+use crate::config::Cli;
+use crate::k8s::{self, ReadyConfig, WatchErrorKind};
+
+/// Something proa needs to be allowed to do to pods.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Permission {
+    /// The verb, like "get" or "patch".
+    pub verb: &'static str,
+    /// The subresource, like "exec", if it isn't the pods themselves.
+    pub subresource: Option<&'static str>,
+}
+
+impl Permission {
+    fn new(verb: &'static str) -> Self {
+        Permission {
+            verb,
+            subresource: None,
+        }
+    }
+
+    /// The resource, as RBAC names it.
+    fn resource(&self) -> String {
+        match self.subresource {
+            Some(subresource) => format!("pods/{}", subresource),
+            None => "pods".to_string(),
+        }
+    }
+}
+
+/// What to check before relying on proa in this Pod.
+#[derive(Clone, Debug)]
+pub struct PreflightConfig {
+    /// How to find and read the Pod.
+    pub ready: ReadyConfig,
+    /// The permissions proa needs, with the options it was given.
+    pub permissions: Vec<Permission>,
+    /// What will happen to the sidecars if no shutdown mechanism is configured.
+    pub missing_shutdown: Option<String>,
+    /// Whether a missing shutdown mechanism is an error, rather than a warning.
+    pub require_shutdown: bool,
+}
+
+impl From<&Cli> for PreflightConfig {
+    fn from(cli: &Cli) -> Self {
+        // Proa gets the Pod, and watches it, which needs list and watch permissions too.
+        let mut permissions = vec![
+            Permission::new("get"),
+            Permission::new("list"),
+            Permission::new("watch"),
+        ];
+        #[cfg(feature = "kube-exec")]
+        if cli.shutdown_exec_container.is_some() {
+            permissions.push(Permission {
+                verb: "create",
+                subresource: Some("exec"),
+            });
+        }
+        if cli.write_exit_annotation.is_some() {
+            permissions.push(Permission::new("patch"));
+        }
+        PreflightConfig {
+            ready: ReadyConfig::from(cli),
+            permissions,
+            missing_shutdown: cli.missing_shutdown(),
+            require_shutdown: cli.require_shutdown,
+        }
+    }
+}
+
+/// Check that proa will be able to do its job in this Pod, without running a command or changing anything. Log the outcome of
+/// each step, with a hint about how to fix any that fail. Return an error if any of them failed.
+#[tracing::instrument(skip_all)]
+pub async fn preflight(preflight_config: &PreflightConfig) -> Result<(), Error> {
+    let config = &preflight_config.ready;
+    let client = match k8s::make_client(config).await {
+        Ok(client) => {
+            info!(step = "connect", "Found the apiserver");
+            client
+        }
+        Err(err) => {
+            error!(
+                step = "connect",
+                err = format!("{:#}", err),
+                hint = "Run proa in a Pod whose service account token is mounted",
+                "Unable to configure a Kubernetes client"
+            );
+            return Err(anyhow!("Preflight checks failed"));
+        }
+    };
+
+    let name = k8s::my_pod_name(config);
+    let namespace = k8s::my_namespace(&client, config);
+    info!(step = "pod name", name, namespace, "Resolved our Pod");
+
+    let mut ok = true;
+    for permission in &preflight_config.permissions {
+        let verb = permission.verb;
+        let resource = permission.resource();
+        match can_i(&client, &namespace, permission).await {
+            Ok(true) => info!(
+                step = "permissions",
+                verb, resource, "Allowed to {} {}", verb, resource
+            ),
+            Ok(false) => {
+                ok = false;
+                error!(
+                    step = "permissions",
+                    verb,
+                    resource,
+                    hint = format!(
+                        "Give the Pod's service account a Role that allows {} on {} in namespace {}",
+                        verb, resource, namespace
+                    ),
+                    "Missing {} {} permission",
+                    resource,
+                    verb
+                );
+            }
+            Err(err) => {
+                ok = false;
+                error!(
+                    step = "permissions",
+                    verb,
+                    resource,
+                    err = err.to_string(),
+                    "Unable to check permissions"
+                );
+            }
+        }
+    }
+
+    let pods_api: Api<Pod> = Api::namespaced(client, &namespace);
+    match pods_api.get(&name).await {
+        Ok(pod) => {
+            info!(step = "get pod", "Fetched our Pod");
+            match k8s::main_cont_names(&pod, config) {
+                Ok(names) => info!(
+                    step = "main containers",
+                    ?names,
+                    "Found the main containers"
+                ),
+                Err(err) => {
+                    ok = false;
+                    error!(
+                        step = "main containers",
+                        err = format!("{:#}", err),
+                        hint = "Check --main-container and --main-container-annotation against the Pod spec",
+                        "Unable to identify the main containers"
+                    );
+                }
+            }
+        }
+        Err(err) => {
+            ok = false;
+            let err = Error::new(err);
+            let kind = WatchErrorKind::classify(&err);
+            error!(
+                step = "get pod",
+                ?kind,
+                err = err.to_string(),
+                hint = kind.guidance(),
+                "Unable to fetch our Pod"
+            );
+        }
+    }
+
+    match &preflight_config.missing_shutdown {
+        None => info!(step = "shutdown", "The sidecars will be shut down"),
+        Some(msg) if preflight_config.require_shutdown => {
+            ok = false;
+            error!(step = "shutdown", "{}", msg);
+        }
+        Some(msg) => warn!(step = "shutdown", "{}", msg),
+    }
+
+    if ok {
+        info!("All preflight checks passed.");
+        Ok(())
+    } else {
+        Err(anyhow!("Preflight checks failed"))
+    }
+}
+
+/// Ask the apiserver whether we're allowed to do something to pods in the namespace. This doesn't change anything.
+async fn can_i(client: &Client, namespace: &str, permission: &Permission) -> Result<bool, Error> {
+    let review = SelfSubjectAccessReview {
+        spec: SelfSubjectAccessReviewSpec {
+            resource_attributes: Some(ResourceAttributes {
+                namespace: Some(namespace.to_string()),
+                verb: Some(permission.verb.to_string()),
+                resource: Some("pods".to_string()),
+                subresource: permission.subresource.map(str::to_string),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let reviews: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    let review = reviews.create(&PostParams::default(), &review).await?;
+    Ok(review.status.map_or(false, |status| status.allowed))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn permissions() -> Result<(), Error> {
+        let verbs = |args: &[&str]| -> Result<Vec<String>, Error> {
+            let cli = Cli::try_parse_from(args)?;
+            Ok(PreflightConfig::from(&cli)
+                .permissions
+                .iter()
+                .map(|p| format!("{} {}", p.verb, p.resource()))
+                .collect())
+        };
+
+        assert_eq!(
+            verbs(&["proa", "true"])?,
+            ["get pods", "list pods", "watch pods"]
+        );
+        assert_eq!(
+            verbs(&["proa", "--write-exit-annotation=proa/exit", "true"])?,
+            ["get pods", "list pods", "watch pods", "patch pods"]
+        );
+        #[cfg(feature = "kube-exec")]
+        assert_eq!(
+            verbs(&[
+                "proa",
+                "--shutdown-exec-container=proxy",
+                "--shutdown-exec-command=kill",
+                "true"
+            ])?,
+            ["get pods", "list pods", "watch pods", "create pods/exec"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_shutdown() -> Result<(), Error> {
+        // Preflight reports a missing shutdown mechanism as one of its steps, rather than refusing to run.
+        let cli = Cli::try_parse_from(["proa", "--require-shutdown", "true"])?;
+        let config = PreflightConfig::from(&cli);
+        assert!(config.missing_shutdown.is_some());
+        assert!(config.require_shutdown);
+
+        let cli = Cli::try_parse_from(["proa", "--quit-port=9091", "true"])?;
+        assert!(PreflightConfig::from(&cli).missing_shutdown.is_none());
+
+        Ok(())
+    }
+}
@@ -1,98 +1,1030 @@
 use anyhow::Error;
+use backoff::backoff::Backoff;
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
 use clap::{crate_name, crate_version};
 use futures::future::join_all;
-use futures::{Future, FutureExt, StreamExt};
+use futures::future::Either;
+use futures::stream::BoxStream;
+use futures::{stream, Stream, StreamExt};
 use k8s_openapi::api::core::v1::Pod;
+use kube::ResourceExt;
 use reqwest::Client;
 use reqwest::{Method, Url};
-use std::time::Duration;
-use tracing::{debug, debug_span, info, warn};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{debug, info, info_span, warn};
 
-use crate::config::Cli;
-use crate::k8s;
+#[cfg(feature = "kill")]
+use crate::config::KillTarget;
+use crate::config::{parse_shutdown_url, Cli, NamedUrl, ShutdownGrace};
+use crate::drain::{self, DrainConfig, Metric};
+use crate::k8s::{self, ReadyConfig};
 use crate::stream::holistic_stream_ext::HolisticStreamExt;
+use crate::stream::holistic_timeout::Elapsed;
+#[cfg(feature = "kill")]
+use nix::sys::signal::Signal;
+
+/// How to shut down the sidecars.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownConfig {
+    /// URLs to GET, to prompt containers to shut down.
+    pub http_get: Vec<NamedUrl>,
+    /// URLs to POST to, to prompt containers to shut down.
+    pub http_post: Vec<NamedUrl>,
+    /// Base URLs of Envoy admin APIs, to drain and then quit.
+    pub envoy_admin: Vec<NamedUrl>,
+    /// A directory of files holding more shutdown URLs, read when it's time to shut down.
+    pub url_dir: Option<PathBuf>,
+    /// Which stage each named shutdown target is shut down in. Every stage finishes before the next one starts, and targets
+    /// that aren't named here are in the first stage.
+    pub stages: HashMap<String, usize>,
+    /// How long to wait for the sidecars to shut down, if shorter than the Pod's termination grace period.
+    pub grace: Option<ShutdownGrace>,
+    /// Where to find a sidecar's active connections, to wait for them to drain before sending the shutdown requests.
+    pub drain: Option<DrainConfig>,
+    /// The most HTTP shutdown requests to have in flight at once. An Envoy's drain and quit count as one. If this isn't set,
+    /// they're all sent at once.
+    pub concurrency: Option<usize>,
+    /// URLs to POST to so sidecars start draining, before the shutdown requests.
+    pub drain_urls: Vec<Url>,
+    /// The drain requests, including Envoy's, were already sent while the main program was exiting, so only the shutdown
+    /// requests are left.
+    pub drained: bool,
+    /// URLs to poll after sending the shutdown requests, until they stop responding successfully.
+    pub confirm_urls: Vec<Url>,
+    /// Wait for the sidecars to report that they aren't ready before waiting for them to exit.
+    pub await_not_ready: bool,
+    /// Status codes that count as success for particular shutdown URLs, besides the 2xx codes.
+    pub expect_status: Vec<(Url, Vec<u16>)>,
+    /// User-Agent for HTTP shutdown requests, with {pod} and {namespace} placeholders. If this isn't set, it's proa's name and
+    /// version.
+    pub user_agent: Option<String>,
+    /// Don't follow redirects from the shutdown URLs.
+    pub no_redirects: bool,
+    /// How many times to retry a failed HTTP shutdown request.
+    pub retries: u32,
+    /// Delay before the first retry of a failed HTTP shutdown request.
+    pub backoff_min: Duration,
+    /// Longest delay between retries of a failed HTTP shutdown request.
+    pub backoff_max: Duration,
+    /// Process names to signal, and which signal to send each of them.
+    #[cfg(feature = "kill")]
+    pub kill: Vec<KillTarget>,
+    /// Files containing the PID of a process to send SIGTERM to.
+    #[cfg(feature = "kill")]
+    pub pidfiles: Vec<PathBuf>,
+    /// Process names, or globs, to leave alone when killing all other processes.
+    #[cfg(feature = "kill")]
+    pub kill_all_except: Vec<String>,
+    /// Only kill processes by name, or all of them, if they're in our own PID namespace.
+    #[cfg(feature = "kill")]
+    pub pidns_only: bool,
+    /// How far into the grace period to signal the processes again, as a percentage. 0 means never.
+    #[cfg(feature = "kill")]
+    pub remind_at: u8,
+    /// How far into the grace period to SIGKILL the processes that are still running, as a percentage. 0 means never.
+    #[cfg(feature = "kill")]
+    pub kill_at: u8,
+    /// A command to run inside a sidecar container.
+    #[cfg(feature = "kube-exec")]
+    pub exec: Option<ExecShutdown>,
+    /// gRPC methods to call with an empty request, as URLs whose paths name the methods.
+    #[cfg(feature = "grpc")]
+    pub grpc: Vec<NamedUrl>,
+    /// How to identify the main containers, which are left out when deciding whether the sidecars have exited.
+    pub ready: ReadyConfig,
+    /// Containers that had already terminated when the shutdown started. Shutdown targets named after them are skipped.
+    pub terminated: HashSet<String>,
+    /// Kill every other process, because no other way to shut down the sidecars was configured. This is worked out by
+    /// [ShutdownConfig::resolve_targets].
+    #[cfg(feature = "kill")]
+    pub kill_everything: bool,
+}
+
+impl From<&Cli> for ShutdownConfig {
+    fn from(cli: &Cli) -> Self {
+        ShutdownConfig {
+            http_get: cli.shutdown_http_get.clone(),
+            http_post: cli.shutdown_http_post_urls(),
+            envoy_admin: cli.envoy_admin.clone(),
+            url_dir: cli.shutdown_url_dir.clone(),
+            // A cycle was already reported when proa started.
+            stages: shutdown_stages(&cli.shutdown_after).unwrap_or_default(),
+            grace: cli.shutdown_grace,
+            drain: drain_config(cli),
+            concurrency: cli.shutdown_concurrency,
+            drain_urls: cli.shutdown_drain_url.clone(),
+            drained: false,
+            confirm_urls: cli.shutdown_confirm_url.clone(),
+            await_not_ready: cli.await_not_ready,
+            expect_status: cli.shutdown_expect_status.clone(),
+            user_agent: cli.shutdown_user_agent.clone(),
+            no_redirects: cli.shutdown_no_redirects,
+            retries: cli.shutdown_retries,
+            backoff_min: Duration::from_millis(cli.shutdown_backoff_min),
+            backoff_max: Duration::from_millis(cli.shutdown_backoff_max),
+            #[cfg(feature = "kill")]
+            kill: cli.kill.clone(),
+            #[cfg(feature = "kill")]
+            pidfiles: cli.shutdown_pidfile.clone(),
+            #[cfg(feature = "kill")]
+            kill_all_except: cli.kill_all_except.clone(),
+            #[cfg(feature = "kill")]
+            pidns_only: cli.kill_pidns_only,
+            #[cfg(feature = "kill")]
+            remind_at: cli.shutdown_remind_at,
+            #[cfg(feature = "kill")]
+            kill_at: cli.shutdown_kill_at,
+            #[cfg(feature = "kube-exec")]
+            exec: cli
+                .shutdown_exec_container
+                .as_ref()
+                .map(|container| ExecShutdown {
+                    container: container.clone(),
+                    command: cli.shutdown_exec_command.clone(),
+                }),
+            #[cfg(feature = "grpc")]
+            grpc: cli.shutdown_grpc.clone(),
+            ready: ReadyConfig::from(cli),
+            terminated: HashSet::new(),
+            #[cfg(feature = "kill")]
+            kill_everything: false,
+        }
+    }
+}
+
+/// Build the drain configuration from the command line, if there is one.
+fn drain_config(cli: &Cli) -> Option<DrainConfig> {
+    let metric = cli
+        .drain_metric_jsonpath
+        .clone()
+        .map(Metric::JsonPath)
+        .or_else(|| cli.drain_metric_name.clone().map(Metric::Prometheus));
+    cli.drain_metric_url
+        .clone()
+        .zip(metric)
+        .map(|(url, metric)| DrainConfig { url, metric })
+}
+
+/// How long to spend fetching the Pod again at the start of the shutdown, before making do with the one we have.
+const REFETCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A command to run inside a sidecar container, to make it shut down.
+#[cfg(feature = "kube-exec")]
+#[derive(Clone, Debug)]
+pub struct ExecShutdown {
+    /// The name of the container to run the command in.
+    pub container: String,
+    /// The command and its arguments.
+    pub command: Vec<String>,
+}
 
 /// Shut down the sidecars and wait for them to terminate.
-pub async fn shutdown(cli: Cli, maybe_pod: Option<Pod>) -> Result<(), Error> {
-    let span = debug_span!("shutdown");
+pub async fn shutdown(mut config: ShutdownConfig, maybe_pod: Option<Pod>) -> Result<(), Error> {
+    let span = info_span!("shutdown", pod_name = Empty, namespace = Empty);
     let _enter = span.enter();
+    if let Some(pod) = &maybe_pod {
+        k8s::record_pod_identity(&pod.name_any(), &pod.namespace().unwrap_or_default());
+    }
 
     info!("Sending shutdown requests.");
 
-    send_shutdown_reqs(cli).await;
-    wait_for_shutdown(maybe_pod).await?;
+    let start = Instant::now();
+    let grace_period = effective_grace(config.grace, k8s::grace_period(maybe_pod.as_ref()));
+    fill_user_agent(&mut config, maybe_pod.as_ref());
+    // The Pod we were given may be from before the main program ran, so look again to see which sidecars have already
+    // exited. That counts against the grace period, so only spend a moment on it.
+    let current_pod =
+        match tokio::time::timeout(REFETCH_TIMEOUT, k8s::get_my_pod(&config.ready)).await {
+            Ok(Ok(pod)) => Some(pod),
+            Ok(Err(err)) => {
+                debug!(err = format!("{:#}", err), "Unable to fetch the Pod again");
+                maybe_pod.clone()
+            }
+            Err(_) => {
+                debug!("Timed out fetching the Pod again");
+                maybe_pod.clone()
+            }
+        };
+    config.terminated = current_pod
+        .as_ref()
+        .map(terminated_containers)
+        .unwrap_or_default();
+    if !config.drained && !config.drain_urls.is_empty() {
+        send_drain_reqs(&config, config.drain_urls.clone(), grace_period).await;
+    }
+    if let Some(drain_config) = &config.drain {
+        match build_client(&config) {
+            Ok(client) => {
+                let remaining = grace_period.saturating_sub(start.elapsed());
+                drain::wait_for_drain(drain_config, &client, remaining).await;
+            }
+            Err(err) => warn!(
+                err = err.to_string(),
+                "Unable to build HTTP client; not waiting for connections to drain."
+            ),
+        }
+    }
+    // The shutdown requests and the escalation have to agree on what was shut down, so work it out once.
+    config.resolve_targets();
+    send_shutdown_reqs(&config, grace_period.saturating_sub(start.elapsed())).await;
+    let wait = async {
+        confirm_shutdowns(&config, grace_period.saturating_sub(start.elapsed())).await;
+        wait_for_shutdown(
+            grace_period.saturating_sub(start.elapsed()),
+            &config.ready,
+            config.await_not_ready,
+        )
+        .await
+    };
+    #[cfg(feature = "kill")]
+    let wait = async {
+        tokio::select! {
+            result = wait => result,
+            _ = escalate(&config, grace_period, start) => Ok(()),
+        }
+    };
+    wait.await?;
 
     Ok(())
 }
 
-/// Send requests for all the other containers in the Pod to shut down.
-async fn send_shutdown_reqs(cli: Cli) {
-    #[cfg(feature = "kill")]
-    send_shutdown_with_kill(cli).await;
-    #[cfg(not(feature = "kill"))]
-    send_shutdown_normal(&cli).await;
+/// A step in escalating a shutdown, after the processes were first signaled.
+#[cfg(feature = "kill")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Escalation {
+    /// Send the processes their signals again.
+    Remind,
+    /// Send the processes SIGKILL.
+    Kill,
 }
 
+/// The kubelet's shutdown timeline, scaled to the grace period: the processes were signaled when the shutdown started, then
+/// they're reminded, and then they're killed. A step at 0% is skipped, as is a reminder that wouldn't come before the kill.
 #[cfg(feature = "kill")]
-async fn send_shutdown_with_kill(cli: Cli) {
-    let no_special_shutdown = cli.shutdown_http_get.is_empty()
-        && cli.shutdown_http_post.is_empty()
-        && cli.kill.is_empty();
+#[derive(Debug)]
+struct Timeline {
+    grace_period: Duration,
+    remind_at: u8,
+    kill_at: u8,
+    /// The last step taken, or None if the processes have only been signaled.
+    last: Option<Escalation>,
+}
+
+#[cfg(feature = "kill")]
+impl Timeline {
+    fn new(grace_period: Duration, remind_at: u8, kill_at: u8) -> Self {
+        Timeline {
+            grace_period,
+            remind_at,
+            kill_at,
+            last: None,
+        }
+    }
+
+    /// Return the next step, and how far into the grace period it's due, or None if there are no steps left.
+    fn next_step(&self) -> Option<(Escalation, Duration)> {
+        let remind = self.remind_at > 0 && (self.kill_at == 0 || self.remind_at < self.kill_at);
+        let next = match self.last {
+            None if remind => Escalation::Remind,
+            None | Some(Escalation::Remind) if self.kill_at > 0 => Escalation::Kill,
+            _ => return None,
+        };
+        let percent = match next {
+            Escalation::Remind => self.remind_at,
+            Escalation::Kill => self.kill_at,
+        };
+        Some((next, self.grace_period * u32::from(percent) / 100))
+    }
+
+    /// Record that a step was taken.
+    fn advance(&mut self, step: Escalation) {
+        self.last = Some(step);
+    }
+}
+
+/// Follow up on the signals sent at the start of the shutdown, on the [Timeline]. This never finishes, so race it against
+/// waiting for the sidecars to exit.
+#[cfg(feature = "kill")]
+async fn escalate(config: &ShutdownConfig, grace_period: Duration, start: Instant) {
+    if !config.kill_everything && config.kill.is_empty() && config.pidfiles.is_empty() {
+        // No processes were signaled, so there's nothing to escalate.
+        return std::future::pending().await;
+    }
+    let mut timeline = Timeline::new(grace_period, config.remind_at, config.kill_at);
+    while let Some((step, due)) = timeline.next_step() {
+        tokio::time::sleep(due.saturating_sub(start.elapsed())).await;
+        match step {
+            Escalation::Remind => {
+                info!("Sidecars are still running; signaling their processes again");
+                signal_processes(config, None);
+            }
+            Escalation::Kill => {
+                warn!(
+                    "Sidecars are still running near the end of the grace period; sending SIGKILL"
+                );
+                signal_processes(config, Some(Signal::SIGKILL));
+            }
+        }
+        timeline.advance(step);
+    }
+    std::future::pending().await
+}
 
-    send_shutdown_normal(&cli).await;
+/// Signal the processes that were signaled when the shutdown started, with the same signals or with `signal` instead. If
+/// nothing else was configured, that's every process.
+#[cfg(feature = "kill")]
+fn signal_processes(config: &ShutdownConfig, signal: Option<Signal>) {
+    if config.kill_everything {
+        kill::kill_all(
+            &config.kill_all_except,
+            config.pidns_only,
+            signal.unwrap_or(Signal::SIGTERM),
+        );
+        return;
+    }
+    for target in &config.kill {
+        let target = KillTarget {
+            name: target.name.clone(),
+            signal: signal.unwrap_or(target.signal),
+        };
+        kill::kill_by_name(&target, config.pidns_only);
+    }
+    for path in &config.pidfiles {
+        kill::kill_by_pidfile(path.clone(), signal.unwrap_or(Signal::SIGTERM));
+    }
+}
 
-    cli.kill.into_iter().for_each(kill::kill_by_name);
+/// Send the drain requests, from --shutdown-drain-url and to each Envoy's /drain_listeners, all at once. This is for
+/// --drain-with-main, while the main program is exiting; the shutdown requests come later, from [shutdown], which should be
+/// told the drain requests were already sent.
+pub async fn drain_with_main(
+    mut config: ShutdownConfig,
+    maybe_pod: Option<&Pod>,
+    grace_period: Duration,
+) {
+    fill_user_agent(&mut config, maybe_pod);
+    let envoy_drains = config.envoy_admin.iter().filter_map(|admin| {
+        admin
+            .url
+            .join("drain_listeners")
+            .map_err(|err| {
+                warn!(
+                    err = err.to_string(),
+                    admin = admin.url.to_string(),
+                    "Unable to build Envoy admin URL"
+                )
+            })
+            .ok()
+    });
+    let urls: Vec<Url> = config
+        .drain_urls
+        .iter()
+        .cloned()
+        .chain(envoy_drains)
+        .collect();
+    if urls.is_empty() {
+        return;
+    }
+    info!("Sending drain requests while the command exits.");
+    send_drain_reqs(&config, urls, grace_period).await;
+}
+
+/// POST to each of the drain URLs at once, retrying failures within the grace period.
+async fn send_drain_reqs(config: &ShutdownConfig, urls: Vec<Url>, grace_period: Duration) {
+    let client = match build_client(config) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!(
+                err = err.to_string(),
+                "Unable to build HTTP client; no drain requests will be sent."
+            );
+            return;
+        }
+    };
+    let retry = RetryPolicy::new(config, grace_period);
+    let drains = urls.into_iter().map(|url| {
+        let expected = config.expected_statuses(&url);
+        send_http(&client, url.clone(), Method::POST, &retry, expected)
+    });
+    join_all(drains).await;
+}
+
+/// Fill in the user agent now that we know who we are, so sidecars can tell which Pod the requests came from.
+fn fill_user_agent(config: &mut ShutdownConfig, maybe_pod: Option<&Pod>) {
+    let pod_name = maybe_pod
+        .map(|pod| pod.name_any())
+        .unwrap_or_else(|| k8s::my_pod_name(&config.ready));
+    let namespace = maybe_pod
+        .and_then(|pod| pod.namespace())
+        .unwrap_or_default();
+    config.user_agent = config
+        .user_agent
+        .take()
+        .map(|template| expand_user_agent(&template, &pod_name, &namespace));
+}
+
+/// Use the configured grace period if there is one, to leave some headroom before the kubelet kills everything. But a longer one
+/// than the Pod's is pointless, since the kubelet won't wait that long.
+fn effective_grace(configured: Option<ShutdownGrace>, pod_grace: Duration) -> Duration {
+    let configured = configured.map(|grace| match grace {
+        ShutdownGrace::Seconds(seconds) => Duration::from_secs(seconds),
+        ShutdownGrace::Percent(percent) => pod_grace * u32::from(percent) / 100,
+    });
+    match configured {
+        Some(grace) if grace > pod_grace => {
+            warn!(
+                ?grace,
+                ?pod_grace,
+                "--shutdown-grace is longer than the Pod's termination grace period; using the Pod's"
+            );
+            pod_grace
+        }
+        Some(grace) => grace,
+        None => pod_grace,
+    }
+}
+
+/// How often to poll a shutdown confirmation URL.
+const CONFIRM_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Some sidecars accept a shutdown request and exit asynchronously. Poll each URL until it stops responding successfully,
+/// meaning its sidecar has gone away, or until the timeout passes.
+async fn confirm_shutdowns(config: &ShutdownConfig, timeout: Duration) {
+    let urls = &config.confirm_urls;
+    if urls.is_empty() {
+        return;
+    }
+    let client = match build_client(config) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!(
+                err = err.to_string(),
+                "Unable to build HTTP client; shutdowns won't be confirmed."
+            );
+            return;
+        }
+    };
+
+    let confirms = urls.iter().map(|url| confirm_shutdown(&client, url));
+    if tokio::time::timeout(timeout, join_all(confirms))
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for sidecars to confirm they've shut down");
+    }
+}
+
+/// Poll one URL until it stops responding successfully.
+async fn confirm_shutdown(client: &Client, url: &Url) {
+    loop {
+        let result = client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        match result {
+            Ok(_) => tokio::time::sleep(CONFIRM_INTERVAL).await,
+            Err(err) => {
+                debug!(
+                    err = err.to_string(),
+                    url = url.to_string(),
+                    "Sidecar confirmed shut down"
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Order the shutdown targets from --shutdown-after pairs, by giving each name a stage number: a name is shut down one stage
+/// after the latest of the names that have to go before it. Return an error if the pairs form a cycle, since then there's no
+/// order that satisfies all of them.
+pub fn shutdown_stages(pairs: &[(String, String)]) -> Result<HashMap<String, usize>, Error> {
+    let mut waiting_on: HashMap<&str, usize> = HashMap::new();
+    let mut followers: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (first, then) in pairs {
+        waiting_on.entry(first).or_default();
+        *waiting_on.entry(then).or_default() += 1;
+        followers.entry(first).or_default().push(then);
+    }
+
+    let mut stages = HashMap::new();
+    let mut ready: Vec<&str> = waiting_on
+        .iter()
+        .filter(|(_, &count)| count == 0)
+        .map(|(&name, _)| name)
+        .collect();
+    while let Some(name) = ready.pop() {
+        let stage = *stages.entry(name.to_string()).or_insert(0);
+        for &then in followers.get(name).into_iter().flatten() {
+            let then_stage = stages.entry(then.to_string()).or_insert(0);
+            *then_stage = (*then_stage).max(stage + 1);
+            let count = waiting_on.get_mut(then).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(then);
+            }
+        }
+    }
+
+    let mut cycle: Vec<&str> = waiting_on
+        .into_iter()
+        .filter(|&(_, count)| count > 0)
+        .map(|(name, _)| name)
+        .collect();
+    if !cycle.is_empty() {
+        cycle.sort_unstable();
+        return Err(anyhow::anyhow!(
+            "--shutdown-after has a cycle, so there's no order to shut down {} in",
+            cycle.join(", ")
+        ));
+    }
+    Ok(stages)
+}
+
+impl ShutdownConfig {
+    /// Return the stage a shutdown target is in.
+    fn stage_of(&self, name: Option<&str>) -> usize {
+        name.and_then(|name| self.stages.get(name))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Return the status codes besides 2xx that count as success for a shutdown URL.
+    fn expected_statuses(&self, url: &Url) -> &[u16] {
+        self.expect_status
+            .iter()
+            .find(|(expected_url, _)| expected_url == url)
+            .map_or(&[], |(_, codes)| codes)
+    }
+
+    /// Settle what to shut down: add the URLs from the URL directory, decide whether to kill everything, and drop the targets
+    /// whose containers already terminated. The directory is only read once, however many times this is called.
+    fn resolve_targets(&mut self) {
+        if let Some(dir) = self.url_dir.take() {
+            for (method, url) in read_url_dir(&dir) {
+                if method == Method::GET {
+                    self.http_get.push(url);
+                } else {
+                    self.http_post.push(url);
+                }
+            }
+        }
+        // Whether to kill everything depends on what was configured, not on what's left after skipping the terminated
+        // containers.
+        #[cfg(feature = "kill")]
+        {
+            self.kill_everything = !has_special_shutdown(self);
+        }
+        self.skip_terminated();
+    }
+
+    /// Drop the shutdown targets named after containers that have already terminated, since there's nothing left to shut down
+    /// and the requests would only fail.
+    fn skip_terminated(&mut self) {
+        let terminated = &self.terminated;
+        let keep = |name: Option<&str>| match name {
+            Some(name) if terminated.contains(name) => {
+                info!(
+                    container = name,
+                    "Skipping the shutdown of a container that already terminated"
+                );
+                false
+            }
+            _ => true,
+        };
+        self.http_get.retain(|target| keep(target.name.as_deref()));
+        self.http_post.retain(|target| keep(target.name.as_deref()));
+        self.envoy_admin
+            .retain(|target| keep(target.name.as_deref()));
+        #[cfg(feature = "grpc")]
+        self.grpc.retain(|target| keep(target.name.as_deref()));
+        #[cfg(feature = "kill")]
+        self.kill.retain(|target| keep(target.name.to_str()));
+        #[cfg(feature = "kube-exec")]
+        if let Some(exec) = &self.exec {
+            if !keep(Some(&exec.container)) {
+                self.exec = None;
+            }
+        }
+    }
+
+    /// Return how many shutdown stages there are.
+    fn stage_count(&self) -> usize {
+        self.stages.values().max().map_or(1, |last| last + 1)
+    }
+}
+
+/// Send requests for all the other containers in the Pod to shut down, one stage at a time. The targets should already be
+/// resolved with [ShutdownConfig::resolve_targets].
+async fn send_shutdown_reqs(config: &ShutdownConfig, grace_period: Duration) {
+    let start = Instant::now();
+    for stage in 0..config.stage_count() {
+        debug!(stage, "Shutting down stage");
+        let remaining = grace_period.saturating_sub(start.elapsed());
+        send_shutdown_stage(config, remaining, stage).await;
+    }
 
     // If given no explicit shutdown instructions, just kill everything.
-    if no_special_shutdown {
-        kill::kill_all();
+    #[cfg(feature = "kill")]
+    if config.kill_everything {
+        kill::kill_all(&config.kill_all_except, config.pidns_only, Signal::SIGTERM);
+    }
+}
+
+/// Read the shutdown URLs from the files in a directory. Each file holds a URL, optionally after a line with the method, and is
+/// named for its file, so --shutdown-after can order it. Files that can't be read or don't hold a URL are logged and skipped,
+/// as are hidden files, which covers the bookkeeping entries in a mounted ConfigMap.
+fn read_url_dir(dir: &Path) -> Vec<(Method, NamedUrl)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(
+                err = err.to_string(),
+                dir = %dir.display(),
+                "Unable to read shutdown URL directory"
+            );
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut urls = Vec::new();
+    for path in paths {
+        let parsed = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| parse_url_file(&contents));
+        match parsed {
+            Ok((method, url)) => {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+                urls.push((method, NamedUrl { name, url }));
+            }
+            Err(err) => warn!(err, path = %path.display(), "Skipping shutdown URL file"),
+        }
     }
+    urls
+}
+
+/// Parse the contents of a shutdown URL file: a URL, optionally after a line with GET or POST. Without a method, it's POST.
+fn parse_url_file(contents: &str) -> Result<(Method, Url), String> {
+    let lines: Vec<&str> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let (method, url) = match lines.as_slice() {
+        [] => return Err("empty file".to_string()),
+        [url] => (Method::POST, *url),
+        [method, url] => match method.to_ascii_uppercase().as_str() {
+            "GET" => (Method::GET, *url),
+            "POST" => (Method::POST, *url),
+            _ => return Err(format!("unsupported method {}", method)),
+        },
+        _ => return Err("expected a URL, optionally after a line with the method".to_string()),
+    };
+    let url = parse_shutdown_url(url).map_err(|err| format!("invalid URL: {}", err))?;
+    Ok((method, url))
+}
+
+/// Return true if any shutdown mechanism was configured, other than killing everything.
+#[cfg(feature = "kill")]
+fn has_special_shutdown(config: &ShutdownConfig) -> bool {
+    #[cfg(feature = "kube-exec")]
+    let exec = config.exec.is_some();
+    #[cfg(not(feature = "kube-exec"))]
+    let exec = false;
+    #[cfg(feature = "grpc")]
+    let grpc = !config.grpc.is_empty();
+    #[cfg(not(feature = "grpc"))]
+    let grpc = false;
+    !config.http_get.is_empty()
+        || !config.http_post.is_empty()
+        || !config.envoy_admin.is_empty()
+        || exec
+        || grpc
+        || !config.kill.is_empty()
+        || !config.pidfiles.is_empty()
+}
+
+/// Send the shutdown requests for the targets in one stage.
+async fn send_shutdown_stage(config: &ShutdownConfig, grace_period: Duration, stage: usize) {
+    let exec = async {
+        #[cfg(feature = "kube-exec")]
+        send_exec_shutdown(config, stage).await;
+    };
+    let grpc = async {
+        #[cfg(feature = "grpc")]
+        send_grpc_shutdowns(config, grace_period, stage).await;
+    };
+    futures::join!(
+        send_http_shutdown_reqs(config, grace_period, stage),
+        exec,
+        grpc
+    );
+
+    #[cfg(feature = "kill")]
+    {
+        config
+            .kill
+            .iter()
+            .filter(|target| config.stage_of(target.name.to_str()) == stage)
+            .for_each(|target| kill::kill_by_name(target, config.pidns_only));
+        if stage == 0 {
+            config
+                .pidfiles
+                .iter()
+                .for_each(|path| kill::kill_by_pidfile(path.clone(), Signal::SIGTERM));
+        }
+    }
+}
+
+/// Run the configured command inside a sidecar container, if it's in this stage, and log what came of it.
+#[cfg(feature = "kube-exec")]
+async fn send_exec_shutdown(config: &ShutdownConfig, stage: usize) {
+    let exec = match &config.exec {
+        Some(exec) if config.stage_of(Some(&exec.container)) == stage => exec,
+        _ => return,
+    };
+    match k8s::exec_in_my_pod(&exec.container, &exec.command, &config.ready).await {
+        Ok(output) if output.status.as_deref() == Some("Success") => info!(
+            container = exec.container,
+            command = ?exec.command,
+            stdout = output.stdout,
+            stderr = output.stderr,
+            "Ran shutdown command"
+        ),
+        Ok(output) => warn!(
+            container = exec.container,
+            command = ?exec.command,
+            stdout = output.stdout,
+            stderr = output.stderr,
+            status = output.status,
+            message = output.message,
+            "Shutdown command failed"
+        ),
+        Err(err) => warn!(
+            err = err.to_string(),
+            container = exec.container,
+            command = ?exec.command,
+            "Unable to run shutdown command"
+        ),
+    }
+}
+
+/// Fill in the {pod} and {namespace} placeholders in a user agent.
+fn expand_user_agent(template: &str, pod_name: &str, namespace: &str) -> String {
+    template
+        .replace("{pod}", pod_name)
+        .replace("{namespace}", namespace)
+}
+
+/// Call the gRPC shutdown methods for one stage, all at once, each within the grace period.
+#[cfg(feature = "grpc")]
+async fn send_grpc_shutdowns(config: &ShutdownConfig, grace_period: Duration, stage: usize) {
+    let calls = config
+        .grpc
+        .iter()
+        .filter(|target| config.stage_of(target.name.as_deref()) == stage)
+        .map(|target| async move {
+            let result = tokio::time::timeout(grace_period, call_grpc(&target.url))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {:?}", grace_period)));
+            match result {
+                Ok(()) => debug!(url = target.url.to_string(), "Sent gRPC shutdown request"),
+                Err(err) => warn!(
+                    err = format!("{:#}", err),
+                    url = target.url.to_string(),
+                    "Error sending gRPC shutdown request"
+                ),
+            }
+        });
+    join_all(calls).await;
 }
 
-async fn send_shutdown_normal(cli: &Cli) {
-    let user_agent = format!("{} v{}", crate_name!(), crate_version!());
-    let client = Client::builder().user_agent(user_agent).build();
+/// Call a gRPC method with an empty request. An empty message is how protobuf encodes a message with every field at its
+/// default, so this works for any unary method whose defaults make sense, not only ones that take `google.protobuf.Empty`.
+/// Whatever the response holds is ignored; only the call's status matters.
+#[cfg(feature = "grpc")]
+async fn call_grpc(url: &Url) -> Result<(), Error> {
+    use tonic::codegen::http::uri::PathAndQuery;
+
+    let channel = tonic::transport::Endpoint::from_shared(url.origin().ascii_serialization())?
+        .connect()
+        .await?;
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await?;
+    let path = PathAndQuery::try_from(url.path())?;
+    let codec = tonic::codec::ProstCodec::<(), ()>::default();
+    grpc.unary(tonic::Request::new(()), path, codec).await?;
+    Ok(())
+}
+
+/// Send the HTTP shutdown requests for one stage. If the HTTP client can't be built, for instance because TLS couldn't be
+/// initialized, try once more and then give up on HTTP. The exec and kill shutdowns don't need the client, so they still happen.
+async fn send_http_shutdown_reqs(config: &ShutdownConfig, grace_period: Duration, stage: usize) {
+    let client = build_client(config);
+    let retry = RetryPolicy::new(config, grace_period);
     match client {
         Err(err) => warn!(
             err = err.to_string(),
             "Unable to build HTTP client; no HTTP shutdown requests will be sent."
         ),
-        Ok(client) => send_http_shutdowns(cli, &client).await,
+        Ok(client) => send_http_shutdowns(config, &client, &retry, stage).await,
     }
 }
 
-fn send_http_shutdowns(cli: &Cli, client: &Client) -> impl Future<Output = ()> {
-    let msgs = cli
-        .shutdown_http_get
-        .iter()
-        .map(|url| send_http(client, url.clone(), Method::GET))
-        .chain(
-            cli.shutdown_http_post
-                .iter()
-                .map(|url| send_http(client, url.clone(), Method::POST)),
+/// Build the HTTP client for drain and shutdown requests, trying twice.
+fn build_client(config: &ShutdownConfig) -> reqwest::Result<Client> {
+    let user_agent = config
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("{} v{}", crate_name!(), crate_version!()));
+    let build = || {
+        let builder = Client::builder().user_agent(user_agent.clone());
+        if config.no_redirects {
+            builder.redirect(reqwest::redirect::Policy::none()).build()
+        } else {
+            builder.build()
+        }
+    };
+    build().or_else(|err| {
+        debug!(
+            err = err.to_string(),
+            "Unable to build HTTP client; trying again"
         );
-    join_all(msgs).map(|_| ())
+        build()
+    })
 }
 
-/// Send an HTTP request. If it fails, log the failure.
-fn send_http(client: &Client, url: Url, method: Method) -> impl Future<Output = ()> {
-    let req = client.request(method.clone(), url.clone());
-    let resp = req.send();
-    resp.map(|r: Result<_, _>| match r {
-        Ok(x) => x.error_for_status(),
-        Err(e) => Err(e),
-    })
-    .map(|r: Result<_, _>| r.err())
-    .then(|x: Option<reqwest::Error>| async move {
-        x.into_iter().for_each(|err| {
-            warn!(
-                err = err.to_string(),
-                url = url.to_string(),
-                ?method,
-                "Error sending shutdown request"
-            )
+async fn send_http_shutdowns(
+    config: &ShutdownConfig,
+    client: &Client,
+    retry: &RetryPolicy,
+    stage: usize,
+) {
+    let in_stage = |target: &&NamedUrl| config.stage_of(target.name.as_deref()) == stage;
+    let msgs = config
+        .http_get
+        .iter()
+        .filter(in_stage)
+        .map(|target| {
+            let expected = config.expected_statuses(&target.url);
+            send_http(client, target.url.clone(), Method::GET, retry, expected)
         })
-    })
+        .chain(config.http_post.iter().filter(in_stage).map(|target| {
+            let expected = config.expected_statuses(&target.url);
+            send_http(client, target.url.clone(), Method::POST, retry, expected)
+        }));
+    let envoys = config
+        .envoy_admin
+        .iter()
+        .filter(in_stage)
+        .map(|admin| shutdown_envoy(config, client, &admin.url, retry));
+    let limit = config.concurrency.unwrap_or(usize::MAX);
+    stream::iter(msgs.map(Either::Left).chain(envoys.map(Either::Right)))
+        .buffer_unordered(limit)
+        .collect::<Vec<()>>()
+        .await;
+}
+
+/// Shut down an Envoy sidecar through its admin API: drain the listeners first so in-flight connections can finish, then quit.
+/// The quit is sent even if the drain fails, since stopping Envoy is what matters most. If the drain was already sent, while the
+/// main program was exiting, only the quit is left.
+async fn shutdown_envoy(
+    config: &ShutdownConfig,
+    client: &Client,
+    admin: &Url,
+    retry: &RetryPolicy,
+) {
+    let paths: &[&str] = if config.drained {
+        &["quitquitquit"]
+    } else {
+        &["drain_listeners", "quitquitquit"]
+    };
+    for path in paths {
+        match admin.join(path) {
+            Ok(url) => {
+                let expected = config.expected_statuses(&url);
+                send_http(client, url.clone(), Method::POST, retry, expected).await
+            }
+            Err(err) => warn!(
+                err = err.to_string(),
+                admin = admin.to_string(),
+                "Unable to build Envoy admin URL"
+            ),
+        }
+    }
+}
+
+/// How to retry HTTP shutdown requests that fail.
+#[derive(Debug)]
+struct RetryPolicy {
+    /// How many times to retry a request after the first attempt.
+    retries: u32,
+    /// The delay before the first retry.
+    min_delay: Duration,
+    /// The longest delay between retries.
+    max_delay: Duration,
+    /// Stop retrying once this much time has passed, so retries can't outlast the grace period.
+    deadline: Duration,
+}
+
+impl RetryPolicy {
+    fn new(config: &ShutdownConfig, grace_period: Duration) -> Self {
+        RetryPolicy {
+            retries: config.retries,
+            min_delay: config.backoff_min,
+            max_delay: config.backoff_max,
+            deadline: grace_period,
+        }
+    }
+
+    /// Exponential backoff with jitter, so retries from many Pods hitting a shared endpoint don't line up.
+    fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.min_delay)
+            .with_max_interval(self.max_delay)
+            .with_max_elapsed_time(Some(self.deadline))
+            .build()
+    }
+}
+
+/// Send an HTTP request, retrying according to the policy. If it still fails, log the failure. A 2xx response is a success, and
+/// so is any of the `expected` status codes, for endpoints that answer with something like 503 because they're shutting down.
+async fn send_http(
+    client: &Client,
+    url: Url,
+    method: Method,
+    retry: &RetryPolicy,
+    expected: &[u16],
+) {
+    let mut backoff = retry.backoff();
+    let mut attempts = 0;
+    loop {
+        let result = client
+            .request(method.clone(), url.clone())
+            .send()
+            .await
+            .and_then(|resp| {
+                if expected.contains(&resp.status().as_u16()) {
+                    Ok(resp)
+                } else {
+                    resp.error_for_status()
+                }
+            });
+        let err = match result {
+            // Only a redirect that wasn't followed, because of --shutdown-no-redirects, gets here.
+            Ok(resp) if resp.status().is_redirection() => {
+                warn!(
+                    url = url.to_string(),
+                    ?method,
+                    status = resp.status().as_u16(),
+                    location = ?resp.headers().get(reqwest::header::LOCATION),
+                    "Shutdown request was redirected; not following it"
+                );
+                return;
+            }
+            Ok(_) => return,
+            Err(err) => err,
+        };
+
+        attempts += 1;
+        match backoff.next_backoff() {
+            Some(delay) if attempts <= retry.retries => {
+                debug!(
+                    err = err.to_string(),
+                    url = url.to_string(),
+                    ?method,
+                    ?delay,
+                    "Retrying shutdown request"
+                );
+                tokio::time::sleep(delay).await;
+            }
+            _ => {
+                warn!(
+                    err = err.to_string(),
+                    url = url.to_string(),
+                    ?method,
+                    attempts,
+                    "Error sending shutdown request"
+                );
+                return;
+            }
+        }
+    }
 }
 
 #[cfg(feature = "kill")]
@@ -101,105 +1033,348 @@ mod kill {
         sys::signal::{self, Signal},
         unistd,
     };
-    use std::ffi::{OsStr, OsString};
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
     use sysinfo::{Pid, PidExt, Process, ProcessExt, System, SystemExt};
-    use tracing::{debug, info, trace};
+    use tracing::{debug, info, trace, warn};
 
-    /// Send a TERM signal to every process that we can see, except our own.
+    use crate::config::{glob_match, KillTarget};
+
+    /// Send a signal to every process that we can see, except our own and any whose executable name matches one of the
+    /// `except` globs. If `pidns_only` is set, only signal processes in our own PID namespace.
     #[tracing::instrument]
-    pub fn kill_all() {
+    pub fn kill_all(except: &[String], pidns_only: bool, sig: Signal) {
         debug!("Killing all visible processes.");
+        let in_scope = match PidnsFilter::new(pidns_only) {
+            Some(filter) => filter,
+            None => return,
+        };
         let mut sys = System::new();
         sys.refresh_processes();
         sys.processes()
             .into_iter()
             .filter(|&(_pid, process)| process.exe().file_name() != Some(OsStr::new("proa")))
-            .for_each(|(pid, proc)| kill_one(pid, proc));
+            .filter(|&(pid, process)| !is_excepted(pid, process, except))
+            .filter(|&(pid, _process)| in_scope.allows(pid))
+            .for_each(|(pid, proc)| kill_one(pid, proc, sig));
+    }
+
+    /// Return true if the process's executable name matches one of the globs in `except`.
+    fn is_excepted(pid: &Pid, process: &Process, except: &[String]) -> bool {
+        let excepted = process.exe().file_name().map_or(false, |name| {
+            let name = name.to_string_lossy();
+            except.iter().any(|pattern| glob_match(pattern, &name))
+        });
+        if excepted {
+            debug!(%pid, name = process.name(), "Not killing excepted process");
+        }
+        excepted
     }
 
-    /// Find any processes running the named executable, and terminate them.
-    pub fn kill_by_name(pname: OsString) {
+    /// Find any processes running the named executable, and send them the target's signal. The name can be a glob. If
+    /// `pidns_only` is set, only signal processes in our own PID namespace.
+    pub fn kill_by_name(target: &KillTarget, pidns_only: bool) {
+        let pattern = target.name.to_string_lossy();
+        let in_scope = match PidnsFilter::new(pidns_only) {
+            Some(filter) => filter,
+            None => return,
+        };
         // It's inefficient to create and refresh sys each time this function is called.
         let mut sys = System::new();
         sys.refresh_processes();
         sys.processes()
             .into_iter()
-            .filter(|&(_pid, process)| process.exe().file_name() == Some(&pname))
-            .for_each(|(pid, proc)| kill_one(pid, proc));
+            .filter(|&(_pid, process)| {
+                process
+                    .exe()
+                    .file_name()
+                    .map_or(false, |name| glob_match(&pattern, &name.to_string_lossy()))
+            })
+            .filter(|&(pid, _process)| in_scope.allows(pid))
+            .for_each(|(pid, proc)| kill_one(pid, proc, target.signal));
+    }
+
+    /// Decides which processes are in scope for killing, by their PID namespace.
+    struct PidnsFilter(Option<PathBuf>);
+
+    impl PidnsFilter {
+        /// Build a filter that allows every process, or if `pidns_only` is set, only the ones in our own PID namespace. Return
+        /// None if we can't tell which namespace we're in, because then it isn't safe to kill anything.
+        fn new(pidns_only: bool) -> Option<Self> {
+            if !pidns_only {
+                return Some(PidnsFilter(None));
+            }
+            match pid_namespace("self") {
+                Some(ns) => Some(PidnsFilter(Some(ns))),
+                None => {
+                    warn!("Unable to read /proc/self/ns/pid, so not killing any processes");
+                    None
+                }
+            }
+        }
+
+        /// Return true if the process may be killed. A process whose namespace can't be read is left alone.
+        fn allows(&self, pid: &Pid) -> bool {
+            match &self.0 {
+                None => true,
+                Some(mine) => {
+                    let theirs = pid_namespace(&pid.to_string());
+                    if theirs.as_ref() != Some(mine) {
+                        debug!(%pid, ?theirs, "Skipping process in another PID namespace");
+                        return false;
+                    }
+                    true
+                }
+            }
+        }
+    }
+
+    /// Return the PID namespace of a process, which is the target of its /proc/PID/ns/pid link, like "pid:[4026531836]".
+    pub fn pid_namespace(pid: &str) -> Option<PathBuf> {
+        std::fs::read_link(format!("/proc/{}/ns/pid", pid)).ok()
+    }
+
+    /// Read a PID from a file, such as one written by a sidecar, and send that process a signal.
+    pub fn kill_by_pidfile(path: PathBuf, sig: Signal) {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                warn!(
+                    err = err.to_string(),
+                    path = %path.display(),
+                    "Unable to read PID file"
+                );
+                return;
+            }
+        };
+        let pid: Pid = match contents.trim().parse() {
+            Ok(pid) => pid,
+            Err(_) => {
+                warn!(path = %path.display(), contents, "PID file doesn't contain a PID");
+                return;
+            }
+        };
+
+        let mut sys = System::new();
+        sys.refresh_process(pid);
+        match sys.process(pid) {
+            Some(process) => kill_one(&pid, process, sig),
+            None => warn!(
+                path = %path.display(),
+                %pid,
+                "PID file is stale; no process has that PID"
+            ),
+        }
     }
 
-    /// Terminate one process by PID. Process is used for log messages.
-    fn kill_one(pid: &Pid, process: &Process) {
-        trace!("Killing PID {} ({})", pid, process.name());
+    /// Send a signal to one process by PID. Process is used for log messages.
+    fn kill_one(pid: &Pid, process: &Process, sig: Signal) {
+        trace!("Sending {} to PID {} ({})", sig, pid, process.name());
         let pid = pid.as_u32();
         let pid = unistd::Pid::from_raw(pid.try_into().unwrap());
-        signal::kill(pid, Signal::SIGTERM)
-            .err()
-            .into_iter()
-            .for_each(|err| {
-                info!(
-                    err = err.desc(),
-                    "Unable to kill PID {} ({})",
-                    pid,
-                    process.name()
-                );
-            });
+        signal::kill(pid, sig).err().into_iter().for_each(|err| {
+            info!(
+                err = err.desc(),
+                "Unable to kill PID {} ({})",
+                pid,
+                process.name()
+            );
+        });
     }
 }
 
+/// How often to log that we're still waiting for the sidecars to exit, when no events are arriving.
+const COUNTDOWN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait before watching the Pod again, if the watch ends while we're waiting for the sidecars to exit.
+const REWATCH_DELAY: Duration = Duration::from_secs(1);
+
 /// Log messages as the containers shut down.
 /// If the timeout expires, give up and log a message.
-async fn wait_for_shutdown(maybe_pod: Option<Pod>) -> Result<(), Error> {
-    let timeout: Option<i64> = maybe_pod.and_then(|pod| {
-        pod.spec
-            .and_then(|spec| spec.termination_grace_period_seconds)
-    });
-    let timeout: u64 = match timeout {
-        Some(x @ 0..) => x.try_into().unwrap(),
-        _ => {
-            debug!("Defaulting to 30 seconds");
-            30
+/// With `await_not_ready`, first wait for the sidecars to stop being ready, within half the timeout.
+async fn wait_for_shutdown(
+    timeout: Duration,
+    config: &ReadyConfig,
+    await_not_ready: bool,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut events = k8s::watch_my_pod(config).await?;
+    if await_not_ready {
+        // The watch may not say anything more if the sidecars already exited, so check the last Pod we saw.
+        let last_pod = wait_for_not_ready_from(&mut events, timeout / 2, config).await;
+        if let Some(pod) = last_pod {
+            if pod_status(&pod, config).0 == Some(0) {
+                return Ok(());
+            }
+        }
+    }
+    let deadline = tokio::time::Instant::now() + timeout.saturating_sub(start.elapsed());
+    wait_for_shutdown_rewatching(events, deadline, config, || k8s::watch_my_pod(config)).await;
+    Ok(())
+}
+
+/// Follow the Pod's events until the sidecars have exited or the deadline passes. The watch should reconnect by itself, but if
+/// the events stop anyway, start another watch with `rewatch`, as long as there's time left.
+async fn wait_for_shutdown_rewatching<F, Fut>(
+    mut events: BoxStream<'static, Result<Option<Pod>, Error>>,
+    deadline: tokio::time::Instant,
+    config: &ReadyConfig,
+    mut rewatch: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<BoxStream<'static, Result<Option<Pod>, Error>>, Error>>,
+{
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if wait_for_shutdown_from(events, remaining, config).await {
+            return;
         }
+        if tokio::time::Instant::now() >= deadline {
+            warn!("The grace period ran out without a working Pod watch; no longer waiting for the sidecars to exit");
+            return;
+        }
+        warn!("The Pod watch ended before the sidecars exited; watching again");
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        tokio::time::sleep(REWATCH_DELAY.min(remaining)).await;
+        events = match rewatch().await {
+            Ok(events) => events,
+            Err(err) => {
+                info!(err = format!("{:#}", err), "Unable to watch the Pod again");
+                stream::empty().boxed()
+            }
+        };
+    }
+}
+
+/// Follow a stream of events about the Pod until none of the sidecars say they're ready, meaning they've stopped serving, or
+/// the timeout expires. Return the Pod, if they got there.
+async fn wait_for_not_ready_from(
+    events: &mut (impl Stream<Item = Result<Option<Pod>, Error>> + Unpin),
+    timeout: Duration,
+    config: &ReadyConfig,
+) -> Option<Pod> {
+    let not_ready = async {
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(Some(pod)) if !any_sidecar_ready(&pod, config) => return Some(pod),
+                Ok(Some(_)) => (),
+                Ok(None) => return None,
+                Err(err) => debug!(
+                    err = err.to_string(),
+                    "Error waiting for sidecars to stop being ready"
+                ),
+            }
+        }
+        None
     };
-    let timeout: Duration = Duration::new(timeout, 0);
+    match tokio::time::timeout(timeout, not_ready).await {
+        Ok(Some(pod)) => {
+            info!("The sidecars have stopped being ready; waiting for them to exit");
+            Some(pod)
+        }
+        Ok(None) => None,
+        Err(_) => {
+            warn!(
+                ?timeout,
+                "Timed out waiting for the sidecars to stop being ready"
+            );
+            None
+        }
+    }
+}
 
-    let events = k8s::watch_my_pod()
-        .await?
+/// Return true if any of the Pod's sidecars says it's ready.
+fn any_sidecar_ready(pod: &Pod, config: &ReadyConfig) -> bool {
+    match k8s::main_cont_names(pod, config) {
+        Ok(names) => k8s::compute_readiness(pod, &names, config)
+            .sidecars
+            .iter()
+            .any(|sidecar| sidecar.ready),
+        Err(_) => true,
+    }
+}
+
+/// Follow a stream of events about the Pod until the sidecars have exited, the Pod is deleted, or the timeout expires, and return
+/// true. Return false if the stream ends first, so another watch can be started.
+async fn wait_for_shutdown_from(
+    events: impl Stream<Item = Result<Option<Pod>, Error>>,
+    timeout: Duration,
+    config: &ReadyConfig,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    // The (running, total) counts from the latest event, for the countdown.
+    let last_status = Cell::new((None, None));
+    let events = events
         .holistic_timeout(timeout)
         .map(flatten_result)
-        .inspect(log_progress)
-        .filter_map(is_done);
+        .inspect(|maybe_pod| {
+            log_progress(maybe_pod, config);
+            if let Ok(Some(pod)) = maybe_pod {
+                last_status.set(pod_status(pod, config));
+            }
+        })
+        .filter_map(|maybe_pod| is_done(maybe_pod, config));
     tokio::pin!(events);
-    if let Some(Err(err)) = events.next().await {
-        info!(err = err.to_string(), "Error waiting for sidecars to exit");
+
+    // A slow shutdown can be quiet, so log a countdown now and then until we're done.
+    let mut countdown = tokio::time::interval_at(
+        tokio::time::Instant::now() + COUNTDOWN_INTERVAL,
+        COUNTDOWN_INTERVAL,
+    );
+    let done = loop {
+        tokio::select! {
+            done = events.next() => break done,
+            _ = countdown.tick() => log_countdown(deadline, last_status.get()),
+        }
+    };
+    match done {
+        Some(Ok(_)) => true,
+        Some(Err(err)) => {
+            info!(err = err.to_string(), "Error waiting for sidecars to exit");
+            true
+        }
+        None => false,
     }
+}
 
-    Ok(())
+/// Log how much of the grace period is left, and how many sidecars were still running as of the latest event.
+fn log_countdown(deadline: Instant, (running, total): (Option<usize>, Option<usize>)) {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    info!(
+        ?remaining,
+        running, total, "Still waiting for sidecar containers to exit"
+    );
 }
 
-/// Use in filter_map to identify the last event in the stream. That's either when all the containers have terminated except one
-/// (which we assume is this one), or when an error occurs.
+/// Use in filter_map to identify the last event in the stream. That's either when all the containers have terminated except the
+/// main ones (one of which is this one), when the Pod is deleted, or when the timeout expires. Other errors, like the watch
+/// dropping, are logged and waited out, since the watch reconnects.
 // We can't just use .status.phase, because that indicates the status of the entire Pod, and we're micro-managing based on statuses
 // of individual conatiners.
-async fn is_done(maybe_pod: Result<Option<Pod>, Error>) -> Option<Result<Pod, Error>> {
+async fn is_done(
+    maybe_pod: Result<Option<Pod>, Error>,
+    config: &ReadyConfig,
+) -> Option<Result<Pod, Error>> {
     match maybe_pod {
         Ok(None) => Some(Err(anyhow::anyhow!(
             "Pod was deleted before this process terminated."
         ))),
         Ok(Some(pod)) => {
-            let (running, _) = pod_status(pod.clone());
-            if running == Some(1) {
+            let (running, _) = pod_status(&pod, config);
+            if running == Some(0) {
                 Some(Ok(pod))
             } else {
                 None
             }
         }
-        Err(e) => Some(Err(e)),
+        Err(e) if e.is::<Elapsed>() => Some(Err(e)),
+        // log_progress already logged it.
+        Err(_) => None,
     }
 }
 
 /// Emit a log message indicating the progress we've made toward shutting down the containers in this pod.
-fn log_progress(maybe_pod: &Result<Option<Pod>, Error>) {
+fn log_progress(maybe_pod: &Result<Option<Pod>, Error>, config: &ReadyConfig) {
     fn fmt_or_unknown(n: Option<usize>) -> String {
         n.map_or("<unknown>".to_string(), |n| format!("{}", n))
     }
@@ -207,30 +1382,73 @@ fn log_progress(maybe_pod: &Result<Option<Pod>, Error>) {
     match maybe_pod {
         Ok(None) => warn!("Pod was deleted, but this process is still running."),
         Ok(Some(pod)) => {
-            let (running, total) = pod_status(pod.clone());
+            let (running, total) = pod_status(pod, config);
             let running = fmt_or_unknown(running);
             let total = fmt_or_unknown(total);
-            debug!("{}/{} containers are still running.", running, total)
+            debug!(
+                "{}/{} sidecar containers are still running.",
+                running, total
+            )
         }
         Err(err) => info!(err = err.to_string()),
     }
 }
 
-/// Return a tuple of (running, total) to show how many of the pod's containers are still running.
-fn pod_status(pod: Pod) -> (Option<usize>, Option<usize>) {
-    // How many containers are still running?
+/// Return the names of the Pod's containers that have terminated.
+fn terminated_containers(pod: &Pod) -> HashSet<String> {
+    pod.status
+        .iter()
+        .flat_map(|status| status.container_statuses.iter().flatten())
+        .filter(|status| {
+            status
+                .state
+                .as_ref()
+                .and_then(|state| state.terminated.as_ref())
+                .is_some()
+        })
+        .map(|status| status.name.clone())
+        .collect()
+}
+
+/// Return a tuple of (running, total) to show how many of the pod's sidecar containers are still running. The main containers
+/// are identified the same way as when waiting for readiness, and aren't counted.
+fn pod_status(pod: &Pod, config: &ReadyConfig) -> (Option<usize>, Option<usize>) {
+    let main_cont_names = match k8s::main_cont_names(pod, config) {
+        Ok(names) => names,
+        Err(err) => {
+            debug!(
+                err = format!("{:#}", err),
+                "Unable to identify the main containers"
+            );
+            return (None, None);
+        }
+    };
+
+    // How many sidecar containers are still running? A waiting one may be about to start again, so it can count too.
     let running: Option<usize> = pod
         .status
-        .and_then(|pod_status| pod_status.container_statuses)
+        .as_ref()
+        .and_then(|pod_status| pod_status.container_statuses.as_ref())
         .map(|c_statuses| {
             c_statuses
-                .into_iter()
-                .flat_map(|c_status| c_status.state.and_then(|c_state| c_state.running))
+                .iter()
+                .filter(|c_status| !main_cont_names.contains(&c_status.name))
+                .filter(|c_status| {
+                    c_status.state.as_ref().map_or(false, |c_state| {
+                        c_state.running.is_some()
+                            || (config.count_waiting && c_state.waiting.is_some())
+                    })
+                })
                 .count()
         });
 
-    // How many containers are there total?
-    let total: Option<usize> = pod.spec.map(|s| s.containers.len());
+    // How many sidecar containers are there total?
+    let total: Option<usize> = pod.spec.as_ref().map(|s| {
+        s.containers
+            .iter()
+            .filter(|c| !main_cont_names.contains(&c.name))
+            .count()
+    });
 
     (running, total)
 }
@@ -250,21 +1468,639 @@ mod tests {
     use super::*;
     use anyhow::anyhow;
     use json::object;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Serve one HTTP response per connection, with each of the given status codes in turn. Return the URL to reach the server,
+    /// and a handle that yields the request line of each request served.
+    async fn fake_server(statuses: Vec<u16>) -> Result<(Url, JoinHandle<Vec<String>>), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/shutdown", listener.local_addr()?))?;
+        let server = tokio::spawn(async move {
+            let mut served = Vec::new();
+            for status in statuses {
+                let mut conn = match listener.accept().await {
+                    Ok((conn, _)) => conn,
+                    Err(_) => break,
+                };
+                let mut buf = [0; 4096];
+                let n = conn.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let request_line = request.lines().next().unwrap_or_default().to_string();
+                let resp = format!(
+                    "HTTP/1.1 {} Whatever\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                let _ = conn.write_all(resp.as_bytes()).await;
+                served.push(request_line);
+            }
+            served
+        });
+        Ok((url, server))
+    }
+
+    #[cfg(all(feature = "kill", target_os = "linux"))]
+    #[test]
+    fn pid_namespace() {
+        let mine = kill::pid_namespace("self");
+        assert!(mine.is_some());
+        assert_eq!(kill::pid_namespace(&std::process::id().to_string()), mine);
+        assert_eq!(kill::pid_namespace("not-a-pid"), None);
+    }
+
+    #[tokio::test]
+    async fn retry_http() -> Result<(), Error> {
+        let client = Client::new();
+        let retry = RetryPolicy {
+            retries: 5,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            deadline: Duration::from_secs(10),
+        };
+
+        // Retry until it succeeds.
+        let (url, server) = fake_server(vec![503, 503, 200]).await?;
+        send_http(&client, url, Method::POST, &retry, &[]).await;
+        assert_eq!(server.await?.len(), 3);
+
+        // Give up after the configured number of retries.
+        let retry = RetryPolicy {
+            retries: 1,
+            ..retry
+        };
+        let (url, server) = fake_server(vec![503, 503]).await?;
+        send_http(&client, url, Method::GET, &retry, &[]).await;
+        assert_eq!(server.await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expected_status() -> Result<(), Error> {
+        let client = Client::new();
+        let retry = RetryPolicy {
+            retries: 5,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            deadline: Duration::from_secs(10),
+        };
+
+        // A 409 was expected, so it isn't retried. The server's second answer goes to our own GET instead.
+        let (url, server) = fake_server(vec![409, 200]).await?;
+        send_http(&client, url.clone(), Method::POST, &retry, &[503, 409]).await;
+        client.get(url.clone()).send().await?;
+        let served = server.await?;
+        assert!(served[0].starts_with("POST"), "{:?}", served);
+        assert!(served[1].starts_with("GET"), "{:?}", served);
+
+        let config = ShutdownConfig {
+            expect_status: vec![(url.clone(), vec![503])],
+            ..Default::default()
+        };
+        assert_eq!(config.expected_statuses(&url), &[503]);
+        assert!(config.expected_statuses(&url.join("other")?).is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_configured_shutdowns() -> Result<(), Error> {
+        let (get_url, get_server) = fake_server(vec![200]).await?;
+        let (post_url, post_server) = fake_server(vec![500, 200]).await?;
+        let config = ShutdownConfig {
+            http_get: vec![get_url.into()],
+            http_post: vec![post_url.into()],
+            retries: 1,
+            ..Default::default()
+        };
+        send_shutdown_reqs(&config, Duration::from_secs(10)).await;
+        assert_eq!(get_server.await?.len(), 1);
+        assert_eq!(post_server.await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stages() {
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .collect()
+        };
+
+        let stages = shutdown_stages(&pairs(&[
+            ("app", "proxy"),
+            ("worker", "proxy"),
+            ("proxy", "mesh"),
+        ]))
+        .unwrap();
+        assert_eq!(stages["app"], 0);
+        assert_eq!(stages["worker"], 0);
+        assert_eq!(stages["proxy"], 1);
+        assert_eq!(stages["mesh"], 2);
+        // The longest chain decides the stage.
+        let stages = shutdown_stages(&pairs(&[("a", "b"), ("b", "c"), ("a", "c")])).unwrap();
+        assert_eq!(stages["c"], 2);
+        assert!(shutdown_stages(&[]).unwrap().is_empty());
+
+        let err = shutdown_stages(&pairs(&[
+            ("app", "proxy"),
+            ("proxy", "mesh"),
+            ("mesh", "proxy"),
+        ]))
+        .unwrap_err();
+        assert!(err.to_string().contains("mesh, proxy"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn ordered_shutdowns() -> Result<(), Error> {
+        // The proxy is only asked to exit once the app has answered.
+        let (app_url, app_server) = fake_server(vec![200]).await?;
+        let (proxy_url, proxy_server) = fake_server(vec![200]).await?;
+        let config = ShutdownConfig {
+            http_post: vec![
+                NamedUrl {
+                    name: Some("proxy".to_string()),
+                    url: proxy_url,
+                },
+                NamedUrl {
+                    name: Some("app".to_string()),
+                    url: app_url,
+                },
+            ],
+            stages: shutdown_stages(&[("app".to_string(), "proxy".to_string())])?,
+            ..Default::default()
+        };
+        assert_eq!(config.stage_count(), 2);
+        send_shutdown_reqs(&config, Duration::from_secs(10)).await;
+        assert_eq!(app_server.await?.len(), 1);
+        assert_eq!(proxy_server.await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn url_dir() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("proa-test-urls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("proxy"), "http://localhost:15000/quitquitquit\n")?;
+        std::fs::write(dir.join("app"), "get\nhttp://localhost:8080/shutdown")?;
+        std::fs::write(dir.join("empty"), "\n")?;
+        std::fs::write(dir.join("junk"), "not a url")?;
+        std::fs::write(dir.join("put"), "PUT\nhttp://localhost:8080/")?;
+        std::fs::write(dir.join(".hidden"), "http://localhost:1/")?;
+        std::fs::create_dir_all(dir.join("..data"))?;
+
+        let urls = read_url_dir(&dir);
+        std::fs::remove_dir_all(&dir)?;
+        let urls: Vec<(Method, Option<&str>, &str)> = urls
+            .iter()
+            .map(|(method, url)| (method.clone(), url.name.as_deref(), url.url.as_str()))
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                (Method::GET, Some("app"), "http://localhost:8080/shutdown"),
+                (
+                    Method::POST,
+                    Some("proxy"),
+                    "http://localhost:15000/quitquitquit"
+                ),
+            ]
+        );
+        assert!(read_url_dir(&dir).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_url_dir_targets() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("proa-test-resolve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("proxy"), "http://localhost:15000/quitquitquit\n")?;
+        std::fs::write(dir.join("sql"), "get\nhttp://localhost:9091/quit")?;
+
+        // The URLs from the directory count as a way to shut down, so nothing else is killed, even once the URL for a
+        // container that already exited is dropped.
+        let mut config = ShutdownConfig {
+            url_dir: Some(dir.clone()),
+            terminated: HashSet::from(["sql".to_string()]),
+            ..Default::default()
+        };
+        config.resolve_targets();
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(config.url_dir, None);
+        assert!(config.http_get.is_empty());
+        assert_eq!(config.http_post.len(), 1);
+        #[cfg(feature = "kill")]
+        assert!(!config.kill_everything);
+
+        #[cfg(feature = "kill")]
+        {
+            let mut config = ShutdownConfig::default();
+            config.resolve_targets();
+            assert!(config.kill_everything);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "kill")]
+    #[tokio::test]
+    async fn kill_without_http_client() -> Result<(), Error> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = tokio::process::Command::new("sleep").arg("30").spawn()?;
+        let path = std::env::temp_dir().join(format!("proa-test-kill-{}.pid", std::process::id()));
+        std::fs::write(&path, child.id().unwrap().to_string())?;
+        let config = ShutdownConfig {
+            http_post: vec![Url::parse("http://127.0.0.1:9/quit")?.into()],
+            // A header can't contain a newline, so the HTTP client can't be built.
+            user_agent: Some("proa\n".to_string()),
+            pidfiles: vec![path.clone()],
+            ..Default::default()
+        };
+        send_shutdown_reqs(&config, Duration::from_secs(10)).await;
+        let _ = std::fs::remove_file(&path);
+
+        // The HTTP shutdown was skipped, but the process was still sent SIGTERM.
+        let status = tokio::time::timeout(Duration::from_secs(10), child.wait()).await??;
+        assert_eq!(status.signal(), Some(15));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "kill")]
+    #[test]
+    fn timeline() {
+        let grace = Duration::from_secs(30);
+        let mut timeline = Timeline::new(grace, 50, 90);
+        assert_eq!(
+            timeline.next_step(),
+            Some((Escalation::Remind, Duration::from_secs(15)))
+        );
+        timeline.advance(Escalation::Remind);
+        assert_eq!(
+            timeline.next_step(),
+            Some((Escalation::Kill, Duration::from_secs(27)))
+        );
+        timeline.advance(Escalation::Kill);
+        assert_eq!(timeline.next_step(), None);
+
+        // Turned off, or not before the kill, there's no reminder.
+        assert_eq!(
+            Timeline::new(grace, 0, 90).next_step(),
+            Some((Escalation::Kill, Duration::from_secs(27)))
+        );
+        assert_eq!(
+            Timeline::new(grace, 95, 90).next_step(),
+            Some((Escalation::Kill, Duration::from_secs(27)))
+        );
+        let mut timeline = Timeline::new(grace, 50, 0);
+        timeline.advance(Escalation::Remind);
+        assert_eq!(timeline.next_step(), None);
+        assert_eq!(Timeline::new(grace, 0, 0).next_step(), None);
+    }
+
+    #[cfg(feature = "kill")]
+    #[tokio::test]
+    async fn escalate_to_kill() -> Result<(), Error> {
+        use std::os::unix::process::ExitStatusExt;
+
+        // This process ignores SIGTERM, so only the escalation to SIGKILL stops it.
+        let mut child = tokio::process::Command::new("sh")
+            .args(["-c", "trap '' TERM; sleep 30"])
+            .spawn()?;
+        let path =
+            std::env::temp_dir().join(format!("proa-test-escalate-{}.pid", std::process::id()));
+        std::fs::write(&path, child.id().unwrap().to_string())?;
+        let config = ShutdownConfig {
+            pidfiles: vec![path.clone()],
+            remind_at: 10,
+            kill_at: 20,
+            ..Default::default()
+        };
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            _ = escalate(&config, Duration::from_secs(5), Instant::now()) => unreachable!(),
+        };
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(status.signal(), Some(9));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limited_concurrency() -> Result<(), Error> {
+        let (url1, server1) = fake_server(vec![200]).await?;
+        let (url2, server2) = fake_server(vec![200]).await?;
+        let config = ShutdownConfig {
+            http_post: vec![url1.into(), url2.into()],
+            concurrency: Some(1),
+            ..Default::default()
+        };
+        send_shutdown_reqs(&config, Duration::from_secs(10)).await;
+        assert_eq!(server1.await?.len(), 1);
+        assert_eq!(server2.await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn confirm_shutdown_polls() -> Result<(), Error> {
+        let config = |url: Url| ShutdownConfig {
+            confirm_urls: vec![url],
+            ..Default::default()
+        };
+
+        // The sidecar answers twice more before it goes away, and then the connection is refused.
+        let (url, server) = fake_server(vec![200, 200]).await?;
+        confirm_shutdowns(&config(url), Duration::from_secs(10)).await;
+        assert_eq!(server.await?.len(), 2);
+
+        // A failure status also means it's done.
+        let (url, server) = fake_server(vec![503]).await?;
+        confirm_shutdowns(&config(url), Duration::from_secs(10)).await;
+        assert_eq!(server.await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn grace() {
+        let pod_grace = Duration::from_secs(30);
+        assert_eq!(effective_grace(None, pod_grace), pod_grace);
+        assert_eq!(
+            effective_grace(Some(ShutdownGrace::Seconds(20)), pod_grace),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            effective_grace(Some(ShutdownGrace::Seconds(60)), pod_grace),
+            pod_grace
+        );
+        assert_eq!(
+            effective_grace(Some(ShutdownGrace::Percent(80)), pod_grace),
+            Duration::from_secs(24)
+        );
+    }
+
+    #[test]
+    fn user_agent() {
+        assert_eq!(
+            expand_user_agent("proa/{namespace}/{pod}", "pod1", "ns1"),
+            "proa/ns1/pod1"
+        );
+        assert_eq!(expand_user_agent("proa", "pod1", "ns1"), "proa");
+    }
+
+    #[tokio::test]
+    async fn envoy_drain_then_quit() -> Result<(), Error> {
+        // The drain fails, but the quit should still be sent afterward.
+        let (url, server) = fake_server(vec![500, 200]).await?;
+        let config = ShutdownConfig {
+            envoy_admin: vec![url.join("/")?.into()],
+            ..Default::default()
+        };
+        send_shutdown_reqs(&config, Duration::from_secs(10)).await;
+        assert_eq!(
+            server.await?,
+            vec![
+                "POST /drain_listeners HTTP/1.1",
+                "POST /quitquitquit HTTP/1.1"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_terminated() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "main" },
+                    { name: "proxy" },
+                    { name: "sql" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "main", state: { terminated: { exitCode: 0 } } },
+                    { name: "proxy", state: { running: {} } },
+                    { name: "sql", state: { terminated: { exitCode: 0 } } },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let terminated = terminated_containers(&pod);
+        assert_eq!(
+            terminated,
+            HashSet::from(["main".to_string(), "sql".to_string()])
+        );
+
+        // Only the target named after a terminated container is dropped; unnamed ones are always kept.
+        let url = Url::parse("http://localhost:8080/quit")?;
+        let named = |name: &str| NamedUrl {
+            name: Some(name.to_string()),
+            url: url.clone(),
+        };
+        let mut config = ShutdownConfig {
+            http_post: vec![named("proxy"), named("sql"), url.clone().into()],
+            envoy_admin: vec![named("sql")],
+            terminated,
+            ..Default::default()
+        };
+        config.skip_terminated();
+        assert_eq!(config.http_post, vec![named("proxy"), url.into()]);
+        assert!(config.envoy_admin.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_with_main_then_quit() -> Result<(), Error> {
+        // Both drains go out while the command exits, and only Envoy's quit is left for the shutdown.
+        let (url, server) = fake_server(vec![200, 200, 200]).await?;
+        let mut config = ShutdownConfig {
+            envoy_admin: vec![url.join("/")?.into()],
+            drain_urls: vec![url.join("/drain")?],
+            ..Default::default()
+        };
+        drain_with_main(config.clone(), None, Duration::from_secs(10)).await;
+        config.drained = true;
+        send_shutdown_reqs(&config, Duration::from_secs(10)).await;
+        let mut served = server.await?;
+        served[..2].sort();
+        assert_eq!(
+            served,
+            vec![
+                "POST /drain HTTP/1.1",
+                "POST /drain_listeners HTTP/1.1",
+                "POST /quitquitquit HTTP/1.1"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_wait_times_out() -> Result<(), Error> {
+        // The sidecar never exits, so we give up at the end of the timeout.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" }
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont2", state: { running: { startedAt: "2020-02-20T02:02:20Z" } } }
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let events = stream::iter([Ok(Some(pod))]).chain(stream::pending());
+        let start = tokio::time::Instant::now();
+        assert!(
+            wait_for_shutdown_from(events, Duration::from_secs(30), &ReadyConfig::default()).await
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_wait_rewatch_fails() -> Result<(), Error> {
+        // The watch ends straight away, and every attempt to watch again fails, so we stop at the deadline.
+        let rewatches = Cell::new(0);
+        let rewatch = || {
+            rewatches.set(rewatches.get() + 1);
+            async { Err(anyhow!("apiserver unreachable")) }
+        };
+        let start = tokio::time::Instant::now();
+        wait_for_shutdown_rewatching(
+            stream::empty().boxed(),
+            start + Duration::from_secs(30),
+            &ReadyConfig::default(),
+            rewatch,
+        )
+        .await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        assert!(rewatches.get() > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_wait_survives_watch_error() -> Result<(), Error> {
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" }
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont2", state: { terminated: { exitCode: 0 } } }
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let config = ReadyConfig::default();
+
+        // The watch drops, then reports that the sidecar exited, so we're done without waiting out the timeout.
+        let events = stream::iter([Err(anyhow!("watch dropped")), Ok(Some(pod.clone()))])
+            .chain(stream::pending());
+        let start = tokio::time::Instant::now();
+        assert!(wait_for_shutdown_from(events, Duration::from_secs(30), &config).await);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // If the stream ends after the error instead, we're not done, and a new watch is needed.
+        let events = stream::iter([Err(anyhow!("watch dropped"))]);
+        assert!(!wait_for_shutdown_from(events, Duration::from_secs(30), &config).await);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn not_ready() -> Result<(), Error> {
+        let pod = |ready: bool| -> Result<Pod, Error> {
+            let pod = object! {
+                apiVersion: "v1",
+                kind: "Pod",
+                metadata: { name: "pod1" },
+                spec: {
+                    containers: [
+                        { name: "cont1" },
+                        { name: "cont2" }
+                    ]
+                },
+                status: {
+                    containerStatuses: [
+                        { name: "cont1", ready: true, state: { running: {} } },
+                        { name: "cont2", ready: ready, state: { running: {} } }
+                    ]
+                }
+            };
+            Ok(serde_json::from_str(pod.dump().as_str())?)
+        };
+        let config = ReadyConfig::default();
+
+        // The sidecar stops being ready, and the main container's readiness doesn't matter.
+        let mut events = stream::iter([Ok(Some(pod(true)?)), Ok(Some(pod(false)?))])
+            .chain(stream::pending())
+            .boxed();
+        let last = wait_for_not_ready_from(&mut events, Duration::from_secs(30), &config).await;
+        assert_eq!(last, Some(pod(false)?));
+
+        // It never does, so we give up at the end of the timeout.
+        let mut events = stream::iter([Ok(Some(pod(true)?))])
+            .chain(stream::pending())
+            .boxed();
+        let start = tokio::time::Instant::now();
+        let last = wait_for_not_ready_from(&mut events, Duration::from_secs(30), &config).await;
+        assert_eq!(last, None);
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+
+        Ok(())
+    }
 
     #[tokio::test]
     async fn test_is_done() -> Result<(), Error> {
-        // An error should be returned.
+        let config = ReadyConfig::default();
+
+        // A watch error isn't the end, since the watch reconnects.
         let result = Err(anyhow!("oops"));
-        let done = is_done(result).await;
+        let done = is_done(result, &config).await;
+        assert!(done.is_none());
+
+        // But running out of time is.
+        let result = Err(Elapsed::new(Duration::from_secs(30)).into());
+        let done = is_done(result, &config).await;
         assert!(done.is_some());
 
         // Our pod was deleted during shutdown. This shouldn't happen since this process is inside the pod; but handle it anyway
         // by signaling done.
         let result = Ok(None);
-        let done = is_done(result).await;
+        let done = is_done(result, &config).await;
         assert!(done.is_some());
 
-        // A pod with one running container.
+        // A pod with only the main container, which is running.
         let pod = object! {
             apiVersion: "v1",
             kind: "Pod",
@@ -278,10 +2114,33 @@ mod tests {
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         let result = Ok(Some(pod));
-        let done = is_done(result).await;
+        let done = is_done(result, &config).await;
         assert!(done.is_some());
 
-        // A pod with two running containers.
+        // A pod with two running containers; the sidecar hasn't exited yet.
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" }
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont2", state: { running: { startedAt: "2020-02-20T02:02:20Z" } } }
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let result = Ok(Some(pod));
+        let done = is_done(result, &config).await;
+        assert!(done.is_none());
+
+        // A pod with two containers, the main one running and the sidecar stopped.
         let pod = object! {
             apiVersion: "v1",
             kind: "Pod",
@@ -293,16 +2152,18 @@ mod tests {
                 ]
             },
             status: {
-                containerStatuses: [ { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } } ],
-                containerStatuses: [ { name: "cont2", state: { running: { startedAt: "2020-02-20T02:02:20Z" } } } ]
+                containerStatuses: [
+                    { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont2", state: { terminated: { exitCode: 0, finishedAt: "2020-02-20T02:02:20Z" } } }
+                ]
             }
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         let result = Ok(Some(pod));
-        let done = is_done(result).await;
+        let done = is_done(result, &config).await;
         assert!(done.is_some());
 
-        // A pod with two containers, one running and one stopped.
+        // A pod whose sidecar is waiting to be restarted. It only counts as still running if we're asked to count waiting ones.
         let pod = object! {
             apiVersion: "v1",
             kind: "Pod",
@@ -314,14 +2175,48 @@ mod tests {
                 ]
             },
             status: {
-                containerStatuses: [ { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } } ],
-                containerStatuses: [ { name: "cont2", state: { terminated: { finishedAt: "2020-02-20T02:02:20Z" } } } ]
+                containerStatuses: [
+                    { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont2", state: { waiting: { reason: "CrashLoopBackOff" } } }
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        assert!(is_done(Ok(Some(pod.clone())), &config).await.is_some());
+        let counting = ReadyConfig {
+            count_waiting: true,
+            ..Default::default()
+        };
+        assert!(is_done(Ok(Some(pod)), &counting).await.is_none());
+
+        // A pod with two long-lived main containers still running, and the sidecar stopped.
+        let config = ReadyConfig {
+            main_containers: vec!["cont1".to_string(), "cont2".to_string()],
+            ..Default::default()
+        };
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                    { name: "cont3" }
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont2", state: { running: { startedAt: "2020-02-02T20:20:02Z" } } },
+                    { name: "cont3", state: { terminated: { exitCode: 0, finishedAt: "2020-02-20T02:02:20Z" } } }
+                ]
             }
         };
         let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
         let result = Ok(Some(pod));
-        let done = is_done(result).await;
-        assert!(done.is_none());
+        let done = is_done(result, &config).await;
+        assert!(done.is_some());
 
         Ok(())
     }
@@ -0,0 +1,461 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Error};
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+use crate::k8s::{ReadinessReport, SidecarReadiness};
+
+// Reporting what proa has seen to other programs, over HTTP or a Unix socket.
+
+/// What proa last saw of the Pod's readiness, and what it's doing now, shared between the rest of proa and the status endpoints.
+/// Cloning it shares the same state.
+#[derive(Clone, Debug)]
+pub struct Status {
+    state: Arc<Mutex<State>>,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+/// How many events to keep for `GET /events` by default.
+pub const DEFAULT_EVENT_BUFFER: usize = 100;
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::new(DEFAULT_EVENT_BUFFER)
+    }
+}
+
+/// What proa is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the sidecars to be ready.
+    Waiting,
+    /// Running the main program.
+    Running,
+    /// Shutting down the sidecars.
+    ShuttingDown,
+}
+
+impl Phase {
+    /// The name of the phase in status messages.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Waiting => "waiting",
+            Phase::Running => "running",
+            Phase::ShuttingDown => "shutting-down",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Whether the Pod was ready for the main program the last time we looked.
+    ready: bool,
+    /// Each sidecar's name, whether it was ready, and what it was doing.
+    sidecars: Vec<SidecarReadiness>,
+    /// Each sidecar's restart count the last time we looked.
+    restart_counts: HashMap<String, i32>,
+    /// How many times the sidecars have restarted while we watched.
+    restarts: u64,
+    /// The most recent events, oldest first, each with when it happened.
+    events: VecDeque<(String, String)>,
+    /// The most events to keep.
+    event_capacity: usize,
+}
+
+impl Status {
+    /// Create a status that keeps up to `event_buffer` of the most recent events.
+    pub fn new(event_buffer: usize) -> Self {
+        Status {
+            state: Arc::new(Mutex::new(State {
+                event_capacity: event_buffer,
+                ..Default::default()
+            })),
+            phase: Arc::new(watch::channel(Phase::Waiting).0),
+        }
+    }
+
+    /// Remember something that happened, like the reason the Pod isn't ready changing, dropping the oldest event if the buffer
+    /// is full.
+    pub fn record_event(&self, event: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.event_capacity == 0 {
+            return;
+        }
+        if state.events.len() >= state.event_capacity {
+            state.events.pop_front();
+        }
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        state.events.push_back((time, event.to_string()));
+    }
+
+    /// List the recent events, oldest first, as JSON.
+    pub fn events_json(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        let events: Vec<_> = state
+            .events
+            .iter()
+            .map(|(time, event)| serde_json::json!({ "time": time, "event": event }))
+            .collect();
+        serde_json::json!({ "events": events })
+    }
+
+    /// Record what we found out about a Pod we saw while waiting for readiness.
+    pub fn observe(&self, report: &ReadinessReport) {
+        let mut state = self.state.lock().unwrap();
+        state.ready = report.ready;
+        state.sidecars = report.sidecars.clone();
+    }
+
+    /// Record each sidecar's restart count, and return the names of the ones that restarted since we last looked, along with
+    /// how many more times they restarted.
+    pub fn record_restarts(
+        &self,
+        pod: &Pod,
+        main_cont_names: &HashSet<String>,
+    ) -> Vec<(String, i32)> {
+        let statuses = pod
+            .status
+            .as_ref()
+            .and_then(|s| s.container_statuses.as_deref())
+            .unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        let mut restarted = Vec::new();
+        for status in statuses
+            .iter()
+            .filter(|s| !main_cont_names.contains(&s.name))
+        {
+            let last = state
+                .restart_counts
+                .insert(status.name.clone(), status.restart_count)
+                .unwrap_or(0);
+            let delta = status.restart_count - last;
+            if delta > 0 {
+                state.restarts += delta as u64;
+                restarted.push((status.name.clone(), delta));
+            }
+        }
+        restarted
+    }
+
+    /// Return true if the Pod was ready the last time we looked.
+    pub fn is_ready(&self) -> bool {
+        self.state.lock().unwrap().ready
+    }
+
+    /// Describe the readiness of the Pod and each of its sidecars, as JSON.
+    pub fn readiness_json(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        serde_json::json!({
+            "ready": state.ready,
+            "restarts": state.restarts,
+            "sidecars": state
+                .sidecars
+                .iter()
+                .map(|sidecar| serde_json::json!({
+                    "name": sidecar.name,
+                    "ready": sidecar.ready,
+                    "state": sidecar.state.as_str(),
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// Record that proa has moved on to a new phase, and tell anyone connected to the status socket.
+    pub fn set_phase(&self, phase: Phase) {
+        debug!(phase = phase.as_str(), "Entering phase");
+        self.record_event(&format!("Entering phase {}", phase.as_str()));
+        self.phase.send_replace(phase);
+    }
+
+    /// Return what proa is doing now.
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+}
+
+/// Serve the status endpoint on the given address until something goes wrong. `GET /readyz` responds 200 if the sidecars are
+/// ready and 503 if they aren't, for use as a probe; `GET /readyz?verbose` adds a JSON body saying which sidecars are ready, and
+/// what each one is doing. `GET /events` lists the most recent events, like the reasons the Pod wasn't ready.
+pub async fn serve(addr: SocketAddr, status: Status) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Unable to listen on {}", addr))?;
+    info!(%addr, "Serving status");
+    serve_listener(listener, status).await
+}
+
+/// Serve the status endpoint on a listener that's already bound.
+async fn serve_listener(listener: TcpListener, status: Status) -> Result<(), Error> {
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let status = status.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(conn, &status).await {
+                debug!(err = err.to_string(), "Error serving status request");
+            }
+        });
+    }
+}
+
+/// Stream proa's phase on a Unix socket at the given path until something goes wrong. Each client gets the current phase as a
+/// line of JSON when it connects, like `{"phase":"waiting"}`, and another line each time the phase changes.
+#[cfg(unix)]
+pub async fn serve_socket(path: &Path, status: Status) -> Result<(), Error> {
+    // A socket left behind by an earlier run would stop us from listening.
+    let _ = std::fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Unable to listen on {}", path.display()))?;
+    info!(path = %path.display(), "Serving status");
+    loop {
+        let (mut conn, _) = listener.accept().await?;
+        let mut phases = status.phase.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let phase = *phases.borrow_and_update();
+                let line = format!("{}\n", serde_json::json!({ "phase": phase.as_str() }));
+                if let Err(err) = conn.write_all(line.as_bytes()).await {
+                    debug!(err = err.to_string(), "Status socket client went away");
+                    break;
+                }
+                if phases.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Read one request from the connection and answer it. Only the request line matters; the rest of the request is ignored.
+async fn respond(conn: TcpStream, status: &Status) -> Result<(), Error> {
+    let mut conn = BufReader::new(conn);
+    let mut request_line = String::new();
+    conn.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    debug!(method, target, "Status request");
+
+    let ready = status.is_ready();
+    let (code, content_type, body) = match (method, path) {
+        ("GET", "/readyz") if query.split('&').any(|q| q == "verbose") => (
+            if ready { 200 } else { 503 },
+            "application/json",
+            format!("{}\n", status.readiness_json()),
+        ),
+        ("GET", "/readyz") if ready => (200, "text/plain", "ready\n".to_string()),
+        ("GET", "/readyz") => (503, "text/plain", "not ready\n".to_string()),
+        ("GET", "/events") => (
+            200,
+            "application/json",
+            format!("{}\n", status.events_json()),
+        ),
+        ("GET", _) => (404, "text/plain", "not found\n".to_string()),
+        _ => (405, "text/plain", "method not allowed\n".to_string()),
+    };
+    let reason = match code {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let resp = format!(
+        "HTTP/1.1 {} {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        code,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+    conn.get_mut().write_all(resp.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use json::object;
+
+    use super::*;
+    use crate::k8s::compute_readiness;
+
+    #[tokio::test]
+    async fn events() -> Result<(), Error> {
+        let status = Status::new(2);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let base = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(serve_listener(listener, status.clone()));
+
+        // Only the two most recent events are kept.
+        status.record_event("first");
+        status.record_event("second");
+        status.set_phase(Phase::Running);
+        let resp = reqwest::get(format!("{}/events", base)).await?;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        let events: Vec<&str> = body["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(events, vec!["second", "Entering phase running"]);
+        assert!(body["events"][0]["time"].is_string());
+
+        // With no buffer, nothing is kept.
+        let status = Status::new(0);
+        status.record_event("first");
+        assert_eq!(status.events_json(), serde_json::json!({ "events": [] }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readyz() -> Result<(), Error> {
+        let status = Status::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let base = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(serve_listener(listener, status.clone()));
+        let client = reqwest::Client::new();
+
+        // Nothing has been seen yet, so it isn't ready.
+        let resp = client.get(format!("{}/readyz", base)).send().await?;
+        assert_eq!(resp.status(), 503);
+
+        let pod = object! {
+            apiVersion: "v1",
+            kind: "Pod",
+            metadata: { name: "pod1" },
+            spec: {
+                containers: [
+                    { name: "cont1" },
+                    { name: "cont2" },
+                    { name: "cont3" },
+                ]
+            },
+            status: {
+                containerStatuses: [
+                    { name: "cont1", ready: false },
+                    { name: "cont2", ready: true },
+                ]
+            }
+        };
+        let pod: Pod = serde_json::from_str(pod.dump().as_str())?;
+        let main = HashSet::from(["cont1".to_string()]);
+        let mut report = compute_readiness(&pod, &main, &Default::default());
+        status.observe(&report);
+        let resp = client
+            .get(format!("{}/readyz?verbose", base))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 503);
+        let body: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "ready": false,
+                "restarts": 0,
+                "sidecars": [
+                    { "name": "cont2", "ready": true, "state": "waiting" },
+                    { "name": "cont3", "ready": false, "state": "missing" },
+                ],
+            })
+        );
+
+        report.ready = true;
+        status.observe(&report);
+        let resp = client.get(format!("{}/readyz", base)).send().await?;
+        assert_eq!(resp.status(), 200);
+        let resp = client.get(format!("{}/other", base)).send().await?;
+        assert_eq!(resp.status(), 404);
+
+        Ok(())
+    }
+
+    #[test]
+    fn restarts() -> Result<(), Error> {
+        let status = Status::default();
+        let main = HashSet::from(["cont1".to_string()]);
+        let pod = |main_restarts: i32, sidecar_restarts: i32| -> Result<Pod, Error> {
+            let pod = object! {
+                apiVersion: "v1",
+                kind: "Pod",
+                metadata: { name: "pod1" },
+                spec: {
+                    containers: [
+                        { name: "cont1" },
+                        { name: "cont2" },
+                    ]
+                },
+                status: {
+                    containerStatuses: [
+                        { name: "cont1", ready: false, restartCount: main_restarts },
+                        { name: "cont2", ready: false, restartCount: sidecar_restarts },
+                    ]
+                }
+            };
+            Ok(serde_json::from_str(pod.dump().as_str())?)
+        };
+
+        assert!(status.record_restarts(&pod(0, 0)?, &main).is_empty());
+        assert_eq!(
+            status.record_restarts(&pod(0, 2)?, &main),
+            vec![("cont2".to_string(), 2)]
+        );
+        // Nothing new, and the main container doesn't count.
+        assert!(status.record_restarts(&pod(5, 2)?, &main).is_empty());
+        assert_eq!(
+            status.record_restarts(&pod(5, 3)?, &main),
+            vec![("cont2".to_string(), 1)]
+        );
+        assert_eq!(status.readiness_json()["restarts"], 3);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn status_socket() -> Result<(), Error> {
+        let status = Status::default();
+        let path = std::env::temp_dir().join(format!("proa-test-{}.sock", std::process::id()));
+        tokio::spawn({
+            let path = path.clone();
+            let status = status.clone();
+            async move { serve_socket(&path, status).await }
+        });
+
+        // Wait for the socket to be listening.
+        let conn = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(conn) => break conn,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let mut lines = BufReader::new(conn).lines();
+        assert_eq!(
+            lines.next_line().await?.as_deref(),
+            Some(r#"{"phase":"waiting"}"#)
+        );
+        status.set_phase(Phase::Running);
+        assert_eq!(
+            lines.next_line().await?.as_deref(),
+            Some(r#"{"phase":"running"}"#)
+        );
+        status.set_phase(Phase::ShuttingDown);
+        assert_eq!(
+            lines.next_line().await?.as_deref(),
+            Some(r#"{"phase":"shutting-down"}"#)
+        );
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
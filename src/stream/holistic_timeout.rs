@@ -23,9 +23,9 @@ pub struct HolisticTimeout<S> {
     poll_deadline: bool,
 }
 
-/// Error returned by `Timeout` and `TimeoutRepeating`.
+/// Error returned by `Timeout` and `TimeoutRepeating`, with the duration of the timeout that elapsed.
 #[derive(Debug, PartialEq, Eq)]
-pub struct Elapsed(());
+pub struct Elapsed(Duration);
 
 impl<S: Stream> HolisticTimeout<S> {
     pub(super) fn new(stream: S, duration: Duration) -> Self {
@@ -60,7 +60,7 @@ impl<S: Stream> Stream for HolisticTimeout<S> {
         if *me.poll_deadline {
             ready!(me.deadline.poll(cx));
             *me.poll_deadline = false;
-            return Poll::Ready(Some(Err(Elapsed::new())));
+            return Poll::Ready(Some(Err(Elapsed::new(*me.duration))));
         }
 
         Poll::Pending
@@ -83,14 +83,19 @@ impl<S: Stream> Stream for HolisticTimeout<S> {
 }
 
 impl Elapsed {
-    pub(crate) fn new() -> Self {
-        Elapsed(())
+    pub(crate) fn new(duration: Duration) -> Self {
+        Elapsed(duration)
+    }
+
+    /// How long the timeout was.
+    pub fn duration(&self) -> Duration {
+        self.0
     }
 }
 
 impl fmt::Display for Elapsed {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        "deadline has elapsed".fmt(fmt)
+        write!(fmt, "deadline of {:?} has elapsed", self.0)
     }
 }
 
@@ -101,3 +106,59 @@ impl From<Elapsed> for std::io::Error {
         std::io::ErrorKind::TimedOut.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::super::holistic_stream_ext::HolisticStreamExt;
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn elapses_once_at_deadline() {
+        let start = Instant::now();
+        let timed = stream::pending::<()>().holistic_timeout(TIMEOUT);
+        tokio::pin!(timed);
+
+        assert_eq!(timed.next().await, Some(Err(Elapsed::new(TIMEOUT))));
+        assert_eq!(start.elapsed(), TIMEOUT);
+        // Nothing more comes after the deadline, however long we wait.
+        assert!(tokio::time::timeout(TIMEOUT * 10, timed.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn items_dont_extend_deadline() {
+        let start = Instant::now();
+        let items = stream::once(async {
+            tokio::time::sleep(TIMEOUT - Duration::from_secs(1)).await;
+            1
+        })
+        .chain(stream::pending());
+        let timed = items.holistic_timeout(TIMEOUT);
+        tokio::pin!(timed);
+
+        assert_eq!(timed.next().await, Some(Ok(1)));
+        assert_eq!(timed.next().await, Some(Err(Elapsed::new(TIMEOUT))));
+        // Unlike tokio's per-item timeout, the deadline is measured from the start of the stream.
+        assert_eq!(start.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_with_stream() {
+        let timed = stream::iter([1, 2]).holistic_timeout(TIMEOUT);
+        let items: Vec<_> = timed.collect().await;
+        assert_eq!(items, vec![Ok(1), Ok(2)]);
+    }
+
+    #[test]
+    fn elapsed_message() {
+        assert_eq!(
+            Elapsed::new(TIMEOUT).to_string(),
+            "deadline of 30s has elapsed"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Tests of the Pod watch, run against a fake apiserver that serves a scripted sequence of responses.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use futures::StreamExt;
+use json::{object, JsonValue};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{Client, Config};
+use proa::config::ListSemantic;
+use proa::k8s::{self, PodMismatch, WatchErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A fake apiserver. Every list request gets `status` and `list` in response. The first watch request gets `events`, and later
+/// watch requests never get a response.
+struct FakeApiserver {
+    status: u16,
+    list: JsonValue,
+    events: Vec<JsonValue>,
+}
+
+impl FakeApiserver {
+    /// Start serving in the background, and return a client that talks to this server.
+    async fn start(self) -> Result<Client, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = Config::new(format!("http://{}", listener.local_addr()?).parse()?);
+        let server = Arc::new(self);
+        let watches = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(serve(conn, server.clone(), watches.clone()));
+            }
+        });
+        Ok(Client::try_from(config)?)
+    }
+}
+
+/// Answer one request.
+async fn serve(mut conn: TcpStream, server: Arc<FakeApiserver>, watches: Arc<AtomicUsize>) {
+    let mut buf = vec![0; 8192];
+    let n = conn.read(&mut buf).await.unwrap_or(0);
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let body = if request.contains("watch=true") {
+        if watches.fetch_add(1, Ordering::SeqCst) > 0 {
+            // Hold the connection open without sending anything, like a watch on a Pod that isn't changing.
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            return;
+        }
+        server
+            .events
+            .iter()
+            .map(|event| event.dump() + "\n")
+            .collect::<String>()
+    } else {
+        server.list.dump()
+    };
+    let resp = format!(
+        "HTTP/1.1 {} Whatever\r\ncontent-type: application/json\r\nconnection: close\r\n\r\n{}",
+        server.status, body
+    );
+    let _ = conn.write_all(resp.as_bytes()).await;
+}
+
+/// A Pod with a main container and one sidecar.
+fn pod(resource_version: &str, sidecar_ready: bool) -> JsonValue {
+    named_pod("pod1", resource_version, sidecar_ready)
+}
+
+/// A Pod with the given name, a main container, and one sidecar.
+fn named_pod(name: &str, resource_version: &str, sidecar_ready: bool) -> JsonValue {
+    object! {
+        apiVersion: "v1",
+        kind: "Pod",
+        metadata: { name: name, namespace: "default", resourceVersion: resource_version },
+        spec: {
+            containers: [
+                { name: "cont1" },
+                { name: "cont2" },
+            ]
+        },
+        status: {
+            containerStatuses: [
+                { name: "cont1", ready: false },
+                { name: "cont2", ready: sidecar_ready },
+            ]
+        }
+    }
+}
+
+fn pod_list(pods: Vec<JsonValue>) -> JsonValue {
+    object! {
+        apiVersion: "v1",
+        kind: "PodList",
+        metadata: { resourceVersion: "1" },
+        items: pods,
+    }
+}
+
+#[tokio::test]
+async fn ready_after_watch_event() -> Result<(), Error> {
+    let client = FakeApiserver {
+        status: 200,
+        list: pod_list(vec![pod("1", false)]),
+        events: vec![object! { "type": "MODIFIED", "object": pod("2", true) }],
+    }
+    .start()
+    .await?;
+
+    let events = k8s::watch_pod(client, "pod1", &Default::default());
+    let ready = tokio::time::timeout(
+        Duration::from_secs(10),
+        k8s::wait_for_ready_from(events, &Default::default()),
+    )
+    .await??;
+    let expected: Pod = serde_json::from_str(pod("2", true).dump().as_str())?;
+    assert_eq!(ready, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ready_after_watch_event_reflector() -> Result<(), Error> {
+    let client = FakeApiserver {
+        status: 200,
+        list: pod_list(vec![pod("1", false)]),
+        events: vec![object! { "type": "MODIFIED", "object": pod("2", true) }],
+    }
+    .start()
+    .await?;
+
+    let events = k8s::reflect_pod(client, "pod1", &Default::default());
+    let ready = tokio::time::timeout(
+        Duration::from_secs(10),
+        k8s::wait_for_ready_from(events, &Default::default()),
+    )
+    .await??;
+    let expected: Pod = serde_json::from_str(pod("2", true).dump().as_str())?;
+    assert_eq!(ready, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn any_list_semantic() -> Result<(), Error> {
+    let client = FakeApiserver {
+        status: 200,
+        list: pod_list(vec![pod("1", true)]),
+        events: vec![],
+    }
+    .start()
+    .await?;
+
+    let config = k8s::ReadyConfig {
+        list_semantic: ListSemantic::Any,
+        ..Default::default()
+    };
+    let events = k8s::watch_pod(client, "pod1", &config);
+    let ready = tokio::time::timeout(
+        Duration::from_secs(10),
+        k8s::wait_for_ready_from(events, &config),
+    )
+    .await??;
+    let expected: Pod = serde_json::from_str(pod("1", true).dump().as_str())?;
+    assert_eq!(ready, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn not_ready_without_event() -> Result<(), Error> {
+    let client = FakeApiserver {
+        status: 200,
+        list: pod_list(vec![pod("1", false)]),
+        events: vec![],
+    }
+    .start()
+    .await?;
+
+    let events = k8s::watch_pod(client, "pod1", &Default::default());
+    let result = tokio::time::timeout(
+        Duration::from_secs(2),
+        k8s::wait_for_ready_from(events, &Default::default()),
+    )
+    .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn forbidden() -> Result<(), Error> {
+    let client = FakeApiserver {
+        status: 403,
+        list: object! {
+            apiVersion: "v1",
+            kind: "Status",
+            metadata: {},
+            status: "Failure",
+            message: "pods \"pod1\" is forbidden",
+            reason: "Forbidden",
+            code: 403,
+        },
+        events: vec![],
+    }
+    .start()
+    .await?;
+
+    let mut events = Box::pin(k8s::watch_pod(client, "pod1", &Default::default()));
+    let err = events.next().await.unwrap().unwrap_err();
+    assert_eq!(WatchErrorKind::classify(&err), WatchErrorKind::Auth);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn wrong_pod() -> Result<(), Error> {
+    let client = FakeApiserver {
+        status: 200,
+        list: pod_list(vec![named_pod("pod2", "1", true)]),
+        events: vec![],
+    }
+    .start()
+    .await?;
+
+    // Even though the Pod is ready, it isn't the one we asked for.
+    let events = k8s::watch_pod(client, "pod1", &Default::default());
+    let err = tokio::time::timeout(
+        Duration::from_secs(10),
+        k8s::wait_for_ready_from(events, &Default::default()),
+    )
+    .await?
+    .unwrap_err();
+    assert!(err.is::<PodMismatch>());
+    assert!(err.to_string().contains("default/pod2"));
+
+    Ok(())
+}
@@ -0,0 +1,210 @@
+//! End-to-end tests that run proa in a real Pod, in a local cluster like kind or minikube. They're ignored by default, since they
+//! need a cluster and an image with proa in it. To run them:
+//!
+//! ```sh
+//! kind create cluster
+//! docker build -t proa:e2e -f skaffold/Dockerfile .
+//! kind load docker-image proa:e2e
+//! PROA_E2E_IMAGE=proa:e2e cargo test --test kind -- --ignored
+//! ```
+//!
+//! The tests use the current kubeconfig context, and the namespace in PROA_E2E_NAMESPACE, or "default".
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use json::object;
+use k8s_openapi::api::core::v1::{Pod, ServiceAccount};
+use k8s_openapi::api::rbac::v1::{Role, RoleBinding};
+use kube::api::{DeleteParams, PostParams};
+use kube::runtime::wait::await_condition;
+use kube::{Api, Client, ResourceExt};
+
+/// The name of the ServiceAccount, Role, and RoleBinding that let proa read its Pod.
+const ACCOUNT: &str = "proa-e2e";
+
+/// How long a Pod gets to pull its images, run, and finish.
+const POD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A sidecar that takes a few seconds to start serving, and exits cleanly on GET /shutdown.
+const SIDECAR: &str = r#"
+import threading, time
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+class Sidecar(BaseHTTPRequestHandler):
+    def do_GET(self):
+        self.send_response(200)
+        self.end_headers()
+        if self.path == "/shutdown":
+            threading.Thread(target=self.server.shutdown).start()
+
+time.sleep(5)
+HTTPServer(("0.0.0.0", 8080), Sidecar).serve_forever()
+"#;
+
+/// The image with proa in it, as its entrypoint.
+fn proa_image() -> Result<String, Error> {
+    std::env::var("PROA_E2E_IMAGE").context(
+        "set PROA_E2E_IMAGE to an image with proa as its entrypoint, loaded into the cluster",
+    )
+}
+
+fn namespace() -> String {
+    std::env::var("PROA_E2E_NAMESPACE").unwrap_or_else(|_| "default".to_string())
+}
+
+/// Treat "already exists" as success, so the tests can share the RBAC objects and be run again.
+fn ignore_conflict<T>(result: Result<T, kube::Error>) -> Result<(), kube::Error> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(resp)) if resp.code == 409 => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Create the ServiceAccount, Role, and RoleBinding that let proa read its Pod, if they don't exist yet.
+async fn ensure_account(client: &Client, namespace: &str) -> Result<(), Error> {
+    let pp = PostParams::default();
+    let account: ServiceAccount = serde_json::from_str(
+        &object! {
+            metadata: { name: ACCOUNT },
+        }
+        .dump(),
+    )?;
+    ignore_conflict(
+        Api::namespaced(client.clone(), namespace)
+            .create(&pp, &account)
+            .await,
+    )?;
+    let role: Role = serde_json::from_str(
+        &object! {
+            metadata: { name: ACCOUNT },
+            rules: [{ apiGroups: [""], resources: ["pods"], verbs: ["get", "watch", "list"] }],
+        }
+        .dump(),
+    )?;
+    ignore_conflict(
+        Api::namespaced(client.clone(), namespace)
+            .create(&pp, &role)
+            .await,
+    )?;
+    let binding: RoleBinding = serde_json::from_str(
+        &object! {
+            metadata: { name: ACCOUNT },
+            subjects: [{ kind: "ServiceAccount", name: ACCOUNT, namespace: namespace }],
+            roleRef: { kind: "Role", name: ACCOUNT, apiGroup: "rbac.authorization.k8s.io" },
+        }
+        .dump(),
+    )?;
+    ignore_conflict(
+        Api::namespaced(client.clone(), namespace)
+            .create(&pp, &binding)
+            .await,
+    )?;
+    Ok(())
+}
+
+/// A Pod that runs `script` under proa, with the slow sidecar.
+fn pod(image: &str, script: &str) -> Result<Pod, Error> {
+    let pod = object! {
+        metadata: { generateName: "proa-e2e-" },
+        spec: {
+            restartPolicy: "Never",
+            serviceAccountName: ACCOUNT,
+            terminationGracePeriodSeconds: 10,
+            containers: [
+                {
+                    name: "main",
+                    image: image,
+                    imagePullPolicy: "IfNotPresent",
+                    args: [
+                        "--shutdown-http-get=http://localhost:8080/shutdown",
+                        "--",
+                        "bash",
+                        "-c",
+                        script,
+                    ],
+                    env: [{ name: "RUST_LOG", value: "proa=debug" }],
+                },
+                {
+                    name: "sidecar",
+                    image: "python:3-alpine",
+                    command: ["python", "-u", "-c", SIDECAR],
+                    readinessProbe: { httpGet: { port: 8080 }, periodSeconds: 1 },
+                },
+            ],
+        },
+    };
+    Ok(serde_json::from_str(&pod.dump())?)
+}
+
+/// Create the Pod, wait for it to finish, then delete it and return it as it was when it finished.
+async fn run_pod(pod: Pod) -> Result<Pod, Error> {
+    let client = Client::try_default().await?;
+    let namespace = namespace();
+    ensure_account(&client, &namespace).await?;
+    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+    let name = pods.create(&PostParams::default(), &pod).await?.name_any();
+
+    let finished = |pod: Option<&Pod>| {
+        let phase = pod
+            .and_then(|pod| pod.status.as_ref())
+            .and_then(|status| status.phase.as_deref());
+        matches!(phase, Some("Succeeded" | "Failed"))
+    };
+    let result =
+        tokio::time::timeout(POD_TIMEOUT, await_condition(pods.clone(), &name, finished)).await;
+    // Clean up even if the Pod didn't finish, so it isn't left running.
+    let _ = pods.delete(&name, &DeleteParams::default()).await;
+    result
+        .map_err(|_| anyhow!("Pod {} didn't finish within {:?}", name, POD_TIMEOUT))??
+        .ok_or_else(|| anyhow!("Pod {} was deleted before it finished", name))
+}
+
+/// Return the exit code of a container in a finished Pod.
+fn exit_code(pod: &Pod, container: &str) -> Option<i32> {
+    pod.status
+        .as_ref()?
+        .container_statuses
+        .as_ref()?
+        .iter()
+        .find(|status| status.name == container)?
+        .state
+        .as_ref()?
+        .terminated
+        .as_ref()
+        .map(|terminated| terminated.exit_code)
+}
+
+#[tokio::test]
+#[ignore = "needs a local cluster and PROA_E2E_IMAGE"]
+async fn waits_runs_and_shuts_down() -> Result<(), Error> {
+    // The command exits with 3 only if the sidecar is already serving, which shows proa waited for it to be ready.
+    let pod = pod(&proa_image()?, "echo > /dev/tcp/127.0.0.1/8080 && exit 3")?;
+    let pod = run_pod(pod).await?;
+
+    assert_eq!(exit_code(&pod, "main"), Some(3));
+    // The sidecar exited on its own after the shutdown request, rather than being killed.
+    assert_eq!(exit_code(&pod, "sidecar"), Some(0));
+    assert_eq!(
+        pod.status.and_then(|status| status.phase).as_deref(),
+        Some("Failed")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs a local cluster and PROA_E2E_IMAGE"]
+async fn succeeds() -> Result<(), Error> {
+    let pod = run_pod(pod(&proa_image()?, "echo > /dev/tcp/127.0.0.1/8080")?).await?;
+
+    assert_eq!(exit_code(&pod, "main"), Some(0));
+    assert_eq!(exit_code(&pod, "sidecar"), Some(0));
+    assert_eq!(
+        pod.status.and_then(|status| status.phase).as_deref(),
+        Some("Succeeded")
+    );
+
+    Ok(())
+}